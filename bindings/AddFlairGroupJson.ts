@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface AddFlairGroupJson { name: string, display_name: string, community_actor_id: string, instance_domain: string, pick_one?: boolean, }
\ No newline at end of file
@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface AddFlairJson { name: string, display_name: string, path: string | null, community_actor_id: string, mod_only: boolean, instance_domain: string, }
\ No newline at end of file
+export interface AddFlairJson { name: string, display_name: string, path: string | null, community_actor_id: string, mod_only: boolean, instance_domain: string, background_color?: string, text_color?: string, text_editable?: boolean, max_length?: number, group_name?: string, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type AuditAction = "flair_created" | "flair_updated" | "flair_deleted" | "flairs_reordered" | "group_created" | "group_updated" | "group_deleted" | "groups_reordered" | "user_flair_assigned" | "user_flair_removed" | "user_flairs_updated" | "flair_requested" | "request_approved" | "request_denied" | "settings_updated";
\ No newline at end of file
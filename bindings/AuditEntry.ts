@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AuditAction } from "./AuditAction";
+
+export interface AuditEntry { id: number, actor_id: string, as_admin: boolean, action: AuditAction, community_actor_id: string, user_actor_id: string | null, flair_name: string | null, before: unknown, after: unknown, created_on: string, }
\ No newline at end of file
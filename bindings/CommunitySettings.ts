@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface CommunitySettings { community_actor_id: string, flairs_enabled: boolean, self_assign: boolean, self_remove: boolean, require_approval: boolean, max_flairs: number, }
\ No newline at end of file
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DailyAssignments } from "./DailyAssignments";
+import type { FlairListing } from "./FlairListing";
+import type { UserFlairHistoryEntry } from "./UserFlairHistoryEntry";
+
+export interface CommunityStats { community_actor_id: string, flaired_users: number, flairs: Array<FlairListing>, assignments_per_day: Array<DailyAssignments>, recent_changes: Array<UserFlairHistoryEntry>, }
\ No newline at end of file
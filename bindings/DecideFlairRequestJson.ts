@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface DecideFlairRequestJson { community_actor_id: string, user_actor_id: string, instance_domain: string, approved: boolean, reason?: string, }
\ No newline at end of file
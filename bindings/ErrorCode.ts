@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ErrorCode = "unauthorized" | "forbidden" | "mod_only" | "not_found" | "unknown_actor" | "validation_failed" | "lemmy_unreachable" | "untrusted_instance" | "db_error";
\ No newline at end of file
@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface Flair { name: string, display_name: string, path: string | null, community_actor_id: string, mod_only: boolean, }
\ No newline at end of file
+export interface Flair { name: string, display_name: string, path: string | null, community_actor_id: string, mod_only: boolean, background_color: string | null, text_color: string | null, text_editable: boolean, max_length: number | null, group_name: string | null, }
\ No newline at end of file
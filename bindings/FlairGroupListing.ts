@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FlairListing } from "./FlairListing";
+
+export interface FlairGroupListing { name: string, display_name: string, community_actor_id: string, pick_one: boolean, flairs: Array<FlairListing>, }
\ No newline at end of file
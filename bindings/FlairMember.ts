@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface FlairMember { user_actor_id: string, custom_text: string | null, assigned_on: string, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { RequestStatus } from "./RequestStatus";
+
+export interface FlairRequest { user_actor_id: string, community_actor_id: string, flair_name: string, custom_text: string | null, status: RequestStatus, reason: string | null, requested_on: string, decided_on: string | null, decided_by: string | null, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AuditAction } from "./AuditAction";
+
+export interface GetAuditLogJson { community_actor_id: string, instance_domain: string, action?: AuditAction, actor_id?: string, user_actor_id?: string, flair_name?: string, before?: number, limit?: number, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface GetCommunityStatsJson { community_actor_id: string, from?: string, to?: string, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface GetFlairMembersJson { community_actor_id: string, instance_domain: string, flair_name: string, cursor?: string, limit?: number, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface GetUserFlairHistoryJson { community_actor_id: string, user_actor_id: string, before?: number, limit?: number, }
\ No newline at end of file
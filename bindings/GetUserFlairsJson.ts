@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { GetUserFlairJson } from "./GetUserFlairJson";
+
+export interface GetUserFlairsJson { community_actor_id: string | null, user_actor_ids: Array<string>, pairs: Array<GetUserFlairJson>, }
\ No newline at end of file
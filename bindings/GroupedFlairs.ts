@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FlairGroupListing } from "./FlairGroupListing";
+import type { FlairListing } from "./FlairListing";
+
+export interface GroupedFlairs { groups: Array<FlairGroupListing>, ungrouped: Array<FlairListing>, }
\ No newline at end of file
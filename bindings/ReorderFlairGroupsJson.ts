@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface ReorderFlairGroupsJson { community_actor_id: string, instance_domain: string, names: Array<string>, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { UserFlairChoice } from "./UserFlairChoice";
+
+export interface SetUserFlairsJson { user_actor_id: string, community_actor_id: string, instance_domain: string, flairs: Array<UserFlairChoice>, }
\ No newline at end of file
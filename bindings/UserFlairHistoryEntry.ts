@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { UserFlairEvent } from "./UserFlairEvent";
+
+export interface UserFlairHistoryEntry { id: number, user_actor_id: string, community_actor_id: string, flair_name: string, custom_text: string | null, event: UserFlairEvent, changed_by: string | null, changed_on: string, }
\ No newline at end of file
@@ -1,14 +1,4 @@
--- Create UserFlairs table if it doesn't exist
-CREATE TABLE IF NOT EXISTS user_flairs (
-    user_actor_id TEXT NOT NULL,
-    flair_name TEXT NOT NULL,
-    flair_community_actor_id TEXT NOT NULL,
-    assigned_on TEXT NOT NULL,
-    FOREIGN KEY (flair_name, flair_community_actor_id) REFERENCES flairs(name, community_actor_id) ON DELETE CASCADE,
-    PRIMARY KEY (user_actor_id, flair_community_actor_id)
-);
-
--- Create Flairs table if it doesn't exist
+-- Tables are only created if missing, as databases set up before migrations existed already have them
 CREATE TABLE IF NOT EXISTS flairs (
     name TEXT NOT NULL,
     display_name TEXT NOT NULL,
@@ -17,3 +7,12 @@ CREATE TABLE IF NOT EXISTS flairs (
     mod_only BOOLEAN NOT NULL,
     PRIMARY KEY(name, community_actor_id)
 );
+
+CREATE TABLE IF NOT EXISTS user_flairs (
+    user_actor_id TEXT NOT NULL,
+    flair_name TEXT NOT NULL,
+    flair_community_actor_id TEXT NOT NULL,
+    assigned_on TEXT NOT NULL,
+    FOREIGN KEY (flair_name, flair_community_actor_id) REFERENCES flairs(name, community_actor_id) ON DELETE CASCADE,
+    PRIMARY KEY (user_actor_id, flair_community_actor_id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE flairs ADD COLUMN background_color TEXT;
+ALTER TABLE flairs ADD COLUMN text_color TEXT;
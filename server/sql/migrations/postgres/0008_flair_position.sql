@@ -0,0 +1,11 @@
+ALTER TABLE flairs ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+
+-- Postgres doesn't keep track of the creation order, existing flairs are sorted by name
+UPDATE flairs SET position = ordered.position
+FROM (
+    SELECT name, community_actor_id, ROW_NUMBER() OVER (PARTITION BY community_actor_id ORDER BY name) - 1 AS position
+    FROM flairs
+) ordered
+WHERE flairs.name = ordered.name AND flairs.community_actor_id = ordered.community_actor_id;
+
+CREATE INDEX flairs_position ON flairs (community_actor_id, position);
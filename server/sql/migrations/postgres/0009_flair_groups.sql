@@ -0,0 +1,10 @@
+CREATE TABLE flair_groups (
+    name TEXT NOT NULL,
+    community_actor_id TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    pick_one BOOLEAN NOT NULL DEFAULT FALSE,
+    position INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (name, community_actor_id)
+);
+
+ALTER TABLE flairs ADD COLUMN group_name TEXT;
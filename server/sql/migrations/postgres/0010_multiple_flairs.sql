@@ -0,0 +1,6 @@
+-- Communities keep allowing a single flair per user until mods raise the limit
+ALTER TABLE community_settings ADD COLUMN max_flairs INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE user_flairs ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE user_flairs DROP CONSTRAINT user_flairs_pkey;
+ALTER TABLE user_flairs ADD PRIMARY KEY (user_actor_id, flair_community_actor_id, flair_name);
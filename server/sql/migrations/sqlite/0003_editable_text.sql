@@ -0,0 +1,3 @@
+ALTER TABLE flairs ADD COLUMN text_editable BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE flairs ADD COLUMN max_length INTEGER;
+ALTER TABLE user_flairs ADD COLUMN custom_text TEXT;
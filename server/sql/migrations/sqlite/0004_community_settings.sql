@@ -0,0 +1,8 @@
+-- Communities without a row use the defaults: flairs enabled, self-service allowed, no approval
+CREATE TABLE community_settings (
+    community_actor_id TEXT NOT NULL PRIMARY KEY,
+    flairs_enabled BOOLEAN NOT NULL DEFAULT 1,
+    self_assign BOOLEAN NOT NULL DEFAULT 1,
+    self_remove BOOLEAN NOT NULL DEFAULT 1,
+    require_approval BOOLEAN NOT NULL DEFAULT 0
+);
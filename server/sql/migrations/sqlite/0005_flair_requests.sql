@@ -0,0 +1,15 @@
+-- Users have at most one request per community, the latest one
+CREATE TABLE flair_requests (
+    user_actor_id TEXT NOT NULL,
+    community_actor_id TEXT NOT NULL,
+    flair_name TEXT NOT NULL,
+    custom_text TEXT,
+    status TEXT NOT NULL,
+    reason TEXT,
+    requested_on TEXT NOT NULL,
+    decided_on TEXT,
+    decided_by TEXT,
+    PRIMARY KEY (user_actor_id, community_actor_id)
+);
+
+CREATE INDEX flair_requests_community_status ON flair_requests (community_actor_id, status);
@@ -0,0 +1,15 @@
+-- Every change to flairs, user flairs, requests and settings, newest entries having the highest id
+CREATE TABLE flair_audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_id TEXT NOT NULL,
+    as_admin BOOLEAN NOT NULL DEFAULT 0,
+    action TEXT NOT NULL,
+    community_actor_id TEXT NOT NULL,
+    user_actor_id TEXT,
+    flair_name TEXT,
+    before_value TEXT,
+    after_value TEXT,
+    created_on TEXT NOT NULL
+);
+
+CREATE INDEX flair_audit_log_community ON flair_audit_log (community_actor_id, id);
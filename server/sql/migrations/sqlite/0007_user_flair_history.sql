@@ -0,0 +1,19 @@
+-- Every flair users received or lost, newest entries having the highest id
+CREATE TABLE user_flair_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_actor_id TEXT NOT NULL,
+    community_actor_id TEXT NOT NULL,
+    flair_name TEXT NOT NULL,
+    custom_text TEXT,
+    event TEXT NOT NULL,
+    changed_by TEXT,
+    changed_on TEXT NOT NULL
+);
+
+CREATE INDEX user_flair_history_user ON user_flair_history (community_actor_id, user_actor_id, id);
+
+-- Flairs assigned before the history existed, nobody knows who assigned them
+INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+    SELECT user_actor_id, flair_community_actor_id, flair_name, custom_text, 'assigned', NULL, assigned_on
+    FROM user_flairs
+    ORDER BY assigned_on;
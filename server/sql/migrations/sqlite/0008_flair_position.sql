@@ -0,0 +1,9 @@
+ALTER TABLE flairs ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+
+-- Existing flairs keep the order they were created in
+UPDATE flairs SET position = (
+    SELECT COUNT(*) FROM flairs f
+    WHERE f.community_actor_id = flairs.community_actor_id AND f.rowid < flairs.rowid
+);
+
+CREATE INDEX flairs_position ON flairs (community_actor_id, position);
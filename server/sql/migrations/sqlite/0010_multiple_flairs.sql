@@ -0,0 +1,22 @@
+-- Communities keep allowing a single flair per user until mods raise the limit
+ALTER TABLE community_settings ADD COLUMN max_flairs INTEGER NOT NULL DEFAULT 1;
+
+-- SQLite can't change the primary key of a table, user_flairs is rebuilt to allow several flairs per user
+CREATE TABLE user_flairs_new (
+    user_actor_id TEXT NOT NULL,
+    flair_name TEXT NOT NULL,
+    flair_community_actor_id TEXT NOT NULL,
+    assigned_on TEXT NOT NULL,
+    custom_text TEXT,
+    position INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (flair_name, flair_community_actor_id) REFERENCES flairs(name, community_actor_id) ON DELETE CASCADE,
+    PRIMARY KEY (user_actor_id, flair_community_actor_id, flair_name)
+);
+
+INSERT INTO user_flairs_new (user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text)
+    SELECT user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text
+    FROM user_flairs;
+
+DROP TABLE user_flairs;
+
+ALTER TABLE user_flairs_new RENAME TO user_flairs;
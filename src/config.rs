@@ -0,0 +1,465 @@
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::verify::{InstancePolicy, LemmyApi};
+
+/// Config is the configuration of Flair, read from an optional TOML file (`--config`)
+/// and overridden by environment variables. It's always validated before being used.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) database: DatabaseConfig,
+    pub(crate) lemmy: LemmyConfig,
+    pub(crate) instances: InstancesConfig,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ServerConfig {
+    /// Port where Flair listens - `FLAIRS_PORT`
+    pub(crate) port: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DatabaseConfig {
+    /// SQLite file path, `postgres://` connection url or `:memory:` - `FLAIR_DB_URL`
+    pub(crate) url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LemmyConfig {
+    /// Domain of the Lemmy instance running next to Flair - `LEMMY_DOMAIN`
+    pub(crate) domain: String,
+    /// Address of the local Lemmy API, without a trailing slash - `LEMMY_INTERNAL_URL`
+    pub(crate) internal_url: String,
+    /// `auto`, or the Lemmy version deciding how JWTs are sent - `LEMMY_API_VERSION`
+    pub(crate) api_version: String,
+    /// Seconds during which verified users are cached, 0 disables caching - `AUTH_CACHE_TTL`
+    pub(crate) auth_cache_ttl: u64,
+    /// Whether communities and users are looked up on Lemmy before being written - `VALIDATE_ACTORS`
+    pub(crate) validate_actors: bool,
+    /// Seconds during which existing communities and users are remembered - `ACTOR_CACHE_TTL`
+    pub(crate) actor_cache_ttl: u64,
+    /// Whether local admins can manage flairs in the local communities, as mods do - `ADMIN_OVERRIDE`
+    pub(crate) admin_override: bool,
+}
+
+impl LemmyConfig {
+    /// Authentication method of every instance, `None` if it should be detected
+    pub(crate) fn api(&self) -> Option<LemmyApi> {
+        LemmyApi::from_version(&self.api_version)
+    }
+}
+
+/// Remote instances trusted to verify the JWTs of their users, the local instance is always trusted
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct InstancesConfig {
+    /// `INSTANCE_POLICY`
+    pub(crate) policy: InstancePolicy,
+    /// Only instances trusted by the `allowlist` policy, lowercase - `INSTANCE_ALLOWLIST`
+    pub(crate) allowlist: Vec<String>,
+    /// Instances rejected by the `denylist` policy, lowercase - `INSTANCE_DENYLIST`
+    pub(crate) denylist: Vec<String>,
+}
+
+impl InstancesConfig {
+    /// Whether a remote instance is trusted, domains being compared case insensitively
+    pub(crate) fn trusts(&self, domain: &str) -> bool {
+        let domain = domain.to_lowercase();
+
+        match self.policy {
+            InstancePolicy::All => true,
+            InstancePolicy::Allowlist => self.allowlist.contains(&domain),
+            InstancePolicy::Denylist => !self.denylist.contains(&domain),
+            InstancePolicy::LocalOnly => false,
+        }
+    }
+}
+
+/// Every problem found in the configuration, so that they can be fixed at once
+#[derive(Debug)]
+pub(crate) struct ConfigError(pub(crate) Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration")?;
+        for problem in &self.0 {
+            write!(f, "\n - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads an environment variable, `None` if it isn't set
+type EnvVars<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Configuration file as written by the admin, every key being optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    server: RawServer,
+    database: RawDatabase,
+    lemmy: RawLemmy,
+    instances: RawInstances,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawServer {
+    port: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDatabase {
+    url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLemmy {
+    domain: Option<String>,
+    internal_url: Option<String>,
+    api_version: Option<String>,
+    auth_cache_ttl: Option<u64>,
+    validate_actors: Option<bool>,
+    actor_cache_ttl: Option<u64>,
+    admin_override: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawInstances {
+    policy: Option<InstancePolicy>,
+    allowlist: Option<Vec<String>>,
+    denylist: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Loads the configuration file, if any, applies the environment overrides and validates the result
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        Self::load_with_env(path, &|var| env::var(var).ok())
+    }
+
+    /// Same as `load`, reading the environment variables through `env` (eg: in tests)
+    pub(crate) fn load_with_env(path: Option<&Path>, env: EnvVars) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+
+        let mut raw = read_file(path)?;
+        raw.apply_env(env, &mut problems);
+        let config = raw.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// Loads the database url alone, so that migrations don't depend on the rest of the configuration
+    pub(crate) fn load_database_url(path: Option<&Path>) -> Result<String, ConfigError> {
+        let mut problems = Vec::new();
+
+        let mut raw = read_file(path)?;
+        override_from_env(
+            &mut raw.database.url,
+            "FLAIR_DB_URL",
+            &|var| env::var(var).ok(),
+            &mut problems,
+        );
+        let url = validate_database_url(raw.database.url, &mut problems);
+
+        if problems.is_empty() {
+            Ok(url)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// The configuration as TOML, with the passwords of the urls replaced by `***`
+    pub(crate) fn redacted(&self) -> String {
+        let mut config = self.clone();
+        config.database.url = redact_url(&config.database.url);
+        config.lemmy.internal_url = redact_url(&config.lemmy.internal_url);
+
+        toml::to_string(&config).unwrap_or_default()
+    }
+}
+
+fn read_file(path: Option<&Path>) -> Result<RawConfig, ConfigError> {
+    let Some(path) = path else {
+        return Ok(RawConfig::default());
+    };
+
+    let content = std::fs::read_to_string(path).map_err(|err| {
+        ConfigError(vec![format!(
+            "unable to read the config file {}: {err}",
+            path.display()
+        )])
+    })?;
+
+    toml::from_str(&content).map_err(|err| {
+        ConfigError(vec![format!(
+            "unable to parse the config file {}: {}",
+            path.display(),
+            err.message()
+        )])
+    })
+}
+
+impl RawConfig {
+    /// Environment variables take precedence over the configuration file
+    fn apply_env(&mut self, env: EnvVars, problems: &mut Vec<String>) {
+        override_from_env(&mut self.server.port, "FLAIRS_PORT", env, problems);
+        override_from_env(&mut self.database.url, "FLAIR_DB_URL", env, problems);
+        override_from_env(&mut self.lemmy.domain, "LEMMY_DOMAIN", env, problems);
+        override_from_env(
+            &mut self.lemmy.internal_url,
+            "LEMMY_INTERNAL_URL",
+            env,
+            problems,
+        );
+        override_from_env(
+            &mut self.lemmy.api_version,
+            "LEMMY_API_VERSION",
+            env,
+            problems,
+        );
+        override_from_env(
+            &mut self.lemmy.auth_cache_ttl,
+            "AUTH_CACHE_TTL",
+            env,
+            problems,
+        );
+        override_from_env(
+            &mut self.lemmy.validate_actors,
+            "VALIDATE_ACTORS",
+            env,
+            problems,
+        );
+        override_from_env(
+            &mut self.lemmy.actor_cache_ttl,
+            "ACTOR_CACHE_TTL",
+            env,
+            problems,
+        );
+        override_from_env(
+            &mut self.lemmy.admin_override,
+            "ADMIN_OVERRIDE",
+            env,
+            problems,
+        );
+        override_from_env(&mut self.instances.policy, "INSTANCE_POLICY", env, problems);
+        override_list_from_env(&mut self.instances.allowlist, "INSTANCE_ALLOWLIST", env);
+        override_list_from_env(&mut self.instances.denylist, "INSTANCE_DENYLIST", env);
+
+        let deprecated = env("DOCKER").is_some() || env("LEMMY_PORT").is_some();
+        if !deprecated {
+            return;
+        }
+        if self.lemmy.internal_url.is_some() {
+            tracing::warn!(
+                "The Lemmy internal url is set, the DOCKER and LEMMY_PORT environment variables will be ignored"
+            );
+            return;
+        }
+
+        tracing::warn!("The DOCKER and LEMMY_PORT environment variables are deprecated, please set LEMMY_INTERNAL_URL instead (eg: http://lemmy:8536)");
+
+        // Flair used to reach Lemmy through its container (DOCKER=true, default) or on the same host
+        let mut port: Option<u16> = None;
+        let mut docker: Option<bool> = None;
+        override_from_env(&mut port, "LEMMY_PORT", env, problems);
+        override_from_env(&mut docker, "DOCKER", env, problems);
+
+        let host = if docker.unwrap_or(true) {
+            "lemmy"
+        } else {
+            "127.0.0.1"
+        };
+        self.lemmy.internal_url = Some(format!("http://{host}:{}", port.unwrap_or(8536)));
+    }
+
+    fn validate(self, problems: &mut Vec<String>) -> Config {
+        let port = self.server.port.unwrap_or(6969);
+        if port == 0 {
+            problems.push(String::from("server.port must not be 0"));
+        }
+
+        let url = validate_database_url(self.database.url, problems);
+
+        let domain = self.lemmy.domain.unwrap_or_default();
+        if domain.is_empty() {
+            problems.push(String::from(
+                "lemmy.domain (LEMMY_DOMAIN) must be set to the domain of your Lemmy instance",
+            ));
+        } else if !is_valid_domain(&domain) {
+            problems.push(format!(
+                "lemmy.domain must be a bare domain (eg: lemmy.ml), not '{domain}'"
+            ));
+        }
+
+        let internal_url = self
+            .lemmy
+            .internal_url
+            .unwrap_or(String::from("http://lemmy:8536"));
+        let internal_url = match parse_lemmy_url(&internal_url) {
+            Ok(url) => url,
+            Err(err) => {
+                problems.push(format!(
+                    "lemmy.internal_url '{}' is invalid: {err}",
+                    redact_url(&internal_url)
+                ));
+                internal_url
+            }
+        };
+
+        let api_version = self.lemmy.api_version.unwrap_or(String::from("auto"));
+        if api_version != "auto" && LemmyApi::from_version(&api_version).is_none() {
+            problems.push(format!(
+                "lemmy.api_version must be 'auto' or a Lemmy version (eg: 0.19), not '{api_version}'"
+            ));
+        }
+
+        let policy = self.instances.policy.unwrap_or(InstancePolicy::All);
+        let allowlist = validate_domains(self.instances.allowlist, "allowlist", problems);
+        let denylist = validate_domains(self.instances.denylist, "denylist", problems);
+        if policy == InstancePolicy::Allowlist && allowlist.is_empty() {
+            problems.push(String::from(
+                "instances.allowlist must list at least one instance with the allowlist policy, use the local_only policy to trust no remote instance",
+            ));
+        }
+
+        Config {
+            server: ServerConfig { port },
+            database: DatabaseConfig { url },
+            lemmy: LemmyConfig {
+                domain,
+                internal_url,
+                api_version,
+                auth_cache_ttl: self.lemmy.auth_cache_ttl.unwrap_or(60),
+                validate_actors: self.lemmy.validate_actors.unwrap_or(false),
+                actor_cache_ttl: self.lemmy.actor_cache_ttl.unwrap_or(3600),
+                admin_override: self.lemmy.admin_override.unwrap_or(true),
+            },
+            instances: InstancesConfig {
+                policy,
+                allowlist,
+                denylist,
+            },
+        }
+    }
+}
+
+/// Replaces a value with the one of an environment variable, if set.
+/// Values that can't be parsed are reported instead of being ignored.
+fn override_from_env<T: FromStr>(
+    value: &mut Option<T>,
+    var: &str,
+    env: EnvVars,
+    problems: &mut Vec<String>,
+) {
+    let Some(raw) = env(var) else {
+        return;
+    };
+
+    // Ports used to be accepted in the `:6969` form
+    let parsed = raw
+        .parse()
+        .or_else(|_| raw.trim().trim_start_matches(':').parse());
+    match parsed {
+        Ok(parsed) => *value = Some(parsed),
+        Err(_) => problems.push(format!("{var} has an invalid value '{raw}'")),
+    }
+}
+
+/// Replaces a list with the comma separated values of an environment variable, if set
+fn override_list_from_env(value: &mut Option<Vec<String>>, var: &str, env: EnvVars) {
+    if let Some(raw) = env(var) {
+        *value = Some(
+            raw.split(',')
+                .map(|el| el.trim().to_string())
+                .filter(|el| !el.is_empty())
+                .collect(),
+        );
+    }
+}
+
+/// Lowercases a list of instance domains, reporting the invalid ones
+fn validate_domains(
+    domains: Option<Vec<String>>,
+    list: &str,
+    problems: &mut Vec<String>,
+) -> Vec<String> {
+    let domains: Vec<String> = domains
+        .unwrap_or_default()
+        .into_iter()
+        .map(|domain| domain.to_lowercase())
+        .collect();
+
+    for domain in domains.iter().filter(|d| !is_valid_domain(d)) {
+        problems.push(format!(
+            "instances.{list} must only contain bare domains (eg: lemmy.ml), not '{domain}'"
+        ));
+    }
+
+    domains
+}
+
+/// Checks whether a string is a domain, optionally followed by a port (eg: `lemmy.ml`, `localhost:8536`)
+pub(crate) fn is_valid_domain(domain: &str) -> bool {
+    let (host, port) = match domain.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (domain, None),
+    };
+
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && port.is_none_or(|p| p.parse::<u16>().is_ok())
+}
+
+fn validate_database_url(url: Option<String>, problems: &mut Vec<String>) -> String {
+    let url = url.unwrap_or(String::from("./database/flairs.db"));
+    if url.trim().is_empty() {
+        problems.push(String::from("database.url must not be empty"));
+    }
+    url
+}
+
+/// Checks that a url points to an HTTP(S) server, dropping the trailing slash so that API paths can be appended
+fn parse_lemmy_url(url: &str) -> Result<String, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!("unsupported scheme '{}'", parsed.scheme()));
+    }
+    if parsed.host_str().is_none() {
+        return Err(String::from("missing host"));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(String::from("query strings and fragments aren't allowed"));
+    }
+
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// Hides the password of a url, leaving anything that isn't a url with a password untouched
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,807 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use flair::{
+    AuditEntry, CommunitySettings, Flair, FlairGroup, FlairMember, FlairRequest, RequestStatus,
+    UserFlair, UserFlairEvent, UserFlairHistoryEntry,
+};
+
+use crate::db::migrations::latest_version;
+use crate::db::{FlairStore, Migration, NewAuditEntry};
+use crate::router::AddFlairGroupJson;
+use crate::router::AddFlairJson;
+use crate::router::AddUserFlairJson;
+use crate::router::DecideFlairRequestJson;
+use crate::router::DeleteFlairGroupJson;
+use crate::router::DeleteFlairJson;
+use crate::router::DeleteUserFlairJson;
+use crate::router::GetAuditLogJson;
+use crate::router::GetFlairsJson;
+use crate::router::GetUserFlairHistoryJson;
+use crate::router::GetUserFlairJson;
+use crate::router::ListUserFlairsJson;
+use crate::router::SetUserFlairsJson;
+
+/// MemoryStore keeps flairs in memory, everything is lost once the server stops.
+/// It's meant for testing handlers without a database.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    data: RwLock<MemoryData>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    /// Flairs, in the order chosen by the mods
+    flairs: Vec<Flair>,
+    /// Flair groups, in the order chosen by the mods
+    flair_groups: Vec<FlairGroup>,
+    user_flairs: Vec<UserFlair>,
+    /// Settings of the communities mods configured
+    community_settings: HashMap<String, CommunitySettings>,
+    /// Flair requests, in submission order
+    requests: Vec<FlairRequest>,
+    /// Audit log, oldest entries first
+    audit_log: Vec<AuditEntry>,
+    /// Flairs users received or lost, oldest entries first
+    user_flair_history: Vec<UserFlairHistoryEntry>,
+}
+
+impl MemoryData {
+    fn find_flair(&self, name: &str, community_actor_id: &str) -> Option<&Flair> {
+        self.flairs
+            .iter()
+            .find(|f| f.name == name && f.community_actor_id == community_actor_id)
+    }
+
+    /// Returns the flairs of a user in the order they're displayed,
+    /// with the display names replaced by their custom text if any
+    fn user_flair_list(&self, user_actor_id: &str, community_actor_id: &str) -> Vec<Flair> {
+        self.user_flairs
+            .iter()
+            .filter(|uf| {
+                uf.user_actor_id == user_actor_id
+                    && uf.flair_community_actor_id == community_actor_id
+            })
+            .filter_map(|uf| {
+                let mut flair = self
+                    .find_flair(&uf.flair_name, &uf.flair_community_actor_id)?
+                    .clone();
+                if let Some(text) = &uf.custom_text {
+                    flair.display_name = text.clone();
+                }
+
+                Some(flair)
+            })
+            .collect()
+    }
+
+    /// Returns the first flair of a user, with the display name replaced by their custom text if any
+    fn user_flair(&self, user_actor_id: &str, community_actor_id: &str) -> Option<Flair> {
+        self.user_flair_list(user_actor_id, community_actor_id)
+            .into_iter()
+            .next()
+    }
+
+    /// Appends an entry to the audit log, callers hold the write lock of the change it describes
+    fn record(&mut self, entry: NewAuditEntry) {
+        let id = self.audit_log.last().map_or(1, |e| e.id + 1);
+        self.audit_log.push(AuditEntry {
+            id,
+            actor_id: entry.actor_id,
+            as_admin: entry.as_admin,
+            action: entry.action,
+            community_actor_id: entry.community_actor_id,
+            user_actor_id: entry.user_actor_id,
+            flair_name: entry.flair_name,
+            before: entry.before,
+            after: entry.after,
+            created_on: Utc::now(),
+        });
+    }
+
+    /// Records a flair a user received or lost in their flair history
+    fn record_history(&mut self, user_flair: &UserFlair, event: UserFlairEvent, changed_by: &str) {
+        let id = self.user_flair_history.last().map_or(1, |e| e.id + 1);
+        self.user_flair_history.push(UserFlairHistoryEntry {
+            id,
+            user_actor_id: user_flair.user_actor_id.clone(),
+            community_actor_id: user_flair.flair_community_actor_id.clone(),
+            flair_name: user_flair.flair_name.clone(),
+            custom_text: user_flair.custom_text.clone(),
+            event,
+            changed_by: Some(changed_by.to_string()),
+            changed_on: Utc::now(),
+        });
+    }
+
+    /// Removes the user flairs matching the predicate, recording the removals in the users' flair history
+    fn remove_user_flairs(&mut self, removed_by: &str, predicate: impl Fn(&UserFlair) -> bool) {
+        let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.user_flairs)
+            .into_iter()
+            .partition(predicate);
+        self.user_flairs = kept;
+
+        for user_flair in &removed {
+            self.record_history(user_flair, UserFlairEvent::Removed, removed_by);
+        }
+    }
+
+    /// Assigns a flair to a user in place of the replaced ones, keeping the other flairs they have in the community.
+    /// The flair keeps its place or takes the one of the first flair it replaces, otherwise it goes last
+    fn assign(&mut self, user_flair: UserFlair, replaced: &[String], assigned_by: &str) {
+        let replaces = |uf: &UserFlair| {
+            uf.user_actor_id == user_flair.user_actor_id
+                && uf.flair_community_actor_id == user_flair.flair_community_actor_id
+                && (uf.flair_name == user_flair.flair_name || replaced.contains(&uf.flair_name))
+        };
+        let index = self.user_flairs.iter().position(replaces);
+        self.user_flairs.retain(|uf| !replaces(uf));
+
+        self.record_history(&user_flair, UserFlairEvent::Assigned, assigned_by);
+        match index {
+            Some(index) => self.user_flairs.insert(index, user_flair),
+            None => self.user_flairs.push(user_flair),
+        }
+    }
+}
+
+impl MemoryStore {
+    fn read(&self) -> anyhow::Result<RwLockReadGuard<'_, MemoryData>> {
+        self.data
+            .read()
+            .map_err(|e| anyhow!("unable to read the in memory store {}", e))
+    }
+
+    fn write(&self) -> anyhow::Result<RwLockWriteGuard<'_, MemoryData>> {
+        self.data
+            .write()
+            .map_err(|e| anyhow!("unable to write the in memory store {}", e))
+    }
+}
+
+#[async_trait]
+impl FlairStore for MemoryStore {
+    fn backend(&self) -> &'static str {
+        "in memory"
+    }
+
+    /// There's no structure to migrate, the in memory store is always up to date
+    async fn schema_version(&self) -> anyhow::Result<i64> {
+        Ok(latest_version())
+    }
+
+    async fn apply_migration(&self, _migration: &Migration) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn get_user_flair(&self, pl: GetUserFlairJson) -> anyhow::Result<Option<Flair>> {
+        let data = self.read()?;
+
+        Ok(data.user_flair(&pl.user_actor_id, &pl.community_actor_id))
+    }
+
+    async fn get_user_flair_list(&self, pl: GetUserFlairJson) -> anyhow::Result<Vec<Flair>> {
+        let data = self.read()?;
+
+        Ok(data.user_flair_list(&pl.user_actor_id, &pl.community_actor_id))
+    }
+
+    async fn list_user_flairs(&self, pl: ListUserFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        let data = self.read()?;
+
+        let mut communities: Vec<&str> = data
+            .user_flairs
+            .iter()
+            .filter(|uf| {
+                uf.user_actor_id == pl.user_actor_id
+                    && pl
+                        .after
+                        .as_deref()
+                        .is_none_or(|a| uf.flair_community_actor_id.as_str() > a)
+            })
+            .map(|uf| uf.flair_community_actor_id.as_str())
+            .collect();
+        communities.sort();
+        communities.dedup();
+
+        Ok(communities
+            .into_iter()
+            .filter_map(|c| data.user_flair(&pl.user_actor_id, c))
+            .take(pl.limit.unwrap_or(u32::MAX) as usize)
+            .collect())
+    }
+
+    async fn get_user_flairs(
+        &self,
+        pairs: Vec<GetUserFlairJson>,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>> {
+        let data = self.read()?;
+
+        let mut val: HashMap<String, HashMap<String, Vec<Flair>>> = HashMap::new();
+        for pl in pairs {
+            let flairs = data.user_flair_list(&pl.user_actor_id, &pl.community_actor_id);
+            if !flairs.is_empty() {
+                val.entry(pl.community_actor_id)
+                    .or_default()
+                    .insert(pl.user_actor_id, flairs);
+            }
+        }
+
+        Ok(val)
+    }
+
+    async fn get_flair(
+        &self,
+        community_actor_id: String,
+        name: String,
+    ) -> anyhow::Result<Option<Flair>> {
+        let data = self.read()?;
+
+        Ok(data.find_flair(&name, &community_actor_id).cloned())
+    }
+
+    async fn get_community_flairs(&self, pl: GetFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        let data = self.read()?;
+        let mod_only = pl.mod_only.unwrap_or(false);
+
+        Ok(data
+            .flairs
+            .iter()
+            .filter(|f| f.community_actor_id == pl.community_actor_id && f.mod_only <= mod_only)
+            .cloned()
+            .collect())
+    }
+
+    async fn reorder_flairs(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        let mut data = self.write()?;
+
+        let (mut community, others): (Vec<Flair>, Vec<Flair>) = std::mem::take(&mut data.flairs)
+            .into_iter()
+            .partition(|f| f.community_actor_id == community_actor_id);
+        let listed =
+            community.len() == names.len() && community.iter().all(|f| names.contains(&f.name));
+        if listed {
+            community.sort_by_key(|f| names.iter().position(|n| n == &f.name));
+        }
+
+        // Only the order within a community matters, flairs are always listed per community
+        data.flairs = others;
+        data.flairs.append(&mut community);
+        if listed {
+            data.record(audit);
+        }
+
+        Ok(listed)
+    }
+
+    async fn get_flair_groups(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairGroup>> {
+        let data = self.read()?;
+
+        Ok(data
+            .flair_groups
+            .iter()
+            .filter(|g| g.community_actor_id == community_actor_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn add_flair_group(
+        &self,
+        pl: AddFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+        let group = FlairGroup {
+            name: pl.name,
+            display_name: pl.display_name,
+            community_actor_id: pl.community_actor_id,
+            pick_one: pl.pick_one.unwrap_or(false),
+        };
+
+        match data
+            .flair_groups
+            .iter_mut()
+            .find(|g| g.name == group.name && g.community_actor_id == group.community_actor_id)
+        {
+            Some(existing) => *existing = group,
+            None => data.flair_groups.push(group),
+        }
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn delete_flair_group(
+        &self,
+        pl: DeleteFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.flair_groups
+            .retain(|g| !(g.name == pl.name && g.community_actor_id == pl.community_actor_id));
+        for flair in data.flairs.iter_mut().filter(|f| {
+            f.group_name.as_ref() == Some(&pl.name) && f.community_actor_id == pl.community_actor_id
+        }) {
+            flair.group_name = None;
+        }
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn reorder_flair_groups(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        let mut data = self.write()?;
+
+        let (mut community, others): (Vec<FlairGroup>, Vec<FlairGroup>) =
+            std::mem::take(&mut data.flair_groups)
+                .into_iter()
+                .partition(|g| g.community_actor_id == community_actor_id);
+        let listed =
+            community.len() == names.len() && community.iter().all(|g| names.contains(&g.name));
+        if listed {
+            community.sort_by_key(|g| names.iter().position(|n| n == &g.name));
+        }
+
+        data.flair_groups = others;
+        data.flair_groups.append(&mut community);
+        if listed {
+            data.record(audit);
+        }
+
+        Ok(listed)
+    }
+
+    async fn count_flair_users(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<HashMap<String, u32>> {
+        let data = self.read()?;
+
+        let mut val: HashMap<String, u32> = HashMap::new();
+        for uf in data
+            .user_flairs
+            .iter()
+            .filter(|uf| uf.flair_community_actor_id == community_actor_id)
+        {
+            *val.entry(uf.flair_name.clone()).or_default() += 1;
+        }
+
+        Ok(val)
+    }
+
+    async fn get_flair_members(
+        &self,
+        community_actor_id: String,
+        flair_name: String,
+        before: Option<(DateTime<Utc>, String)>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<FlairMember>> {
+        let data = self.read()?;
+
+        let mut members: Vec<&UserFlair> = data
+            .user_flairs
+            .iter()
+            .filter(|uf| {
+                uf.flair_community_actor_id == community_actor_id
+                    && uf.flair_name == flair_name
+                    && before.as_ref().is_none_or(|(date, user)| {
+                        (uf.assigned_on, &uf.user_actor_id) < (*date, user)
+                    })
+            })
+            .collect();
+        members.sort_by(|a, b| {
+            (b.assigned_on, &b.user_actor_id).cmp(&(a.assigned_on, &a.user_actor_id))
+        });
+
+        Ok(members
+            .into_iter()
+            .take(limit as usize)
+            .map(|uf| FlairMember {
+                user_actor_id: uf.user_actor_id.clone(),
+                custom_text: uf.custom_text.clone(),
+                assigned_on: uf.assigned_on,
+            })
+            .collect())
+    }
+
+    async fn add_flair(&self, pl: AddFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+        let flair = Flair::new(
+            pl.name,
+            pl.display_name,
+            pl.path,
+            pl.community_actor_id,
+            pl.mod_only,
+            pl.background_color,
+            pl.text_color,
+            pl.text_editable.unwrap_or(false),
+            pl.max_length,
+            pl.group_name,
+        );
+
+        // Locking the text brings every user back to the flair's display name
+        if !flair.text_editable {
+            for uf in data.user_flairs.iter_mut().filter(|uf| {
+                uf.flair_name == flair.name
+                    && uf.flair_community_actor_id == flair.community_actor_id
+            }) {
+                uf.custom_text = None;
+            }
+        }
+
+        match data
+            .flairs
+            .iter_mut()
+            .find(|f| f.name == flair.name && f.community_actor_id == flair.community_actor_id)
+        {
+            Some(existing) => *existing = flair,
+            None => data.flairs.push(flair),
+        }
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn delete_flair(&self, pl: DeleteFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.flairs
+            .retain(|f| !(f.name == pl.name && f.community_actor_id == pl.community_actor_id));
+        data.remove_user_flairs(&audit.actor_id, |uf| {
+            uf.flair_name == pl.name && uf.flair_community_actor_id == pl.community_actor_id
+        });
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn assign_user_flair(
+        &self,
+        pl: AddUserFlairJson,
+        replaced: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.assign(
+            UserFlair::new(
+                pl.user_actor_id,
+                pl.flair_name,
+                pl.community_actor_id,
+                Utc::now(),
+                pl.custom_text,
+            ),
+            &replaced,
+            &audit.actor_id,
+        );
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn delete_user_flair(
+        &self,
+        pl: DeleteUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.remove_user_flairs(&audit.actor_id, |uf| {
+            uf.user_actor_id == pl.user_actor_id
+                && uf.flair_community_actor_id == pl.community_actor_id
+        });
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn set_user_flairs(
+        &self,
+        pl: SetUserFlairsJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        let (mut current, others): (Vec<UserFlair>, Vec<UserFlair>) =
+            std::mem::take(&mut data.user_flairs)
+                .into_iter()
+                .partition(|uf| {
+                    uf.user_actor_id == pl.user_actor_id
+                        && uf.flair_community_actor_id == pl.community_actor_id
+                });
+        data.user_flairs = others;
+
+        current.sort_by(|a, b| a.flair_name.cmp(&b.flair_name));
+        for user_flair in &current {
+            if !pl
+                .flairs
+                .iter()
+                .any(|f| f.flair_name == user_flair.flair_name)
+            {
+                data.record_history(user_flair, UserFlairEvent::Removed, &audit.actor_id);
+            }
+        }
+
+        for choice in pl.flairs {
+            let kept = current.iter().find(|uf| {
+                uf.flair_name == choice.flair_name && uf.custom_text == choice.custom_text
+            });
+
+            match kept {
+                Some(user_flair) => data.user_flairs.push(user_flair.clone()),
+                None => {
+                    let user_flair = UserFlair::new(
+                        pl.user_actor_id.clone(),
+                        choice.flair_name,
+                        pl.community_actor_id.clone(),
+                        Utc::now(),
+                        choice.custom_text,
+                    );
+                    data.record_history(&user_flair, UserFlairEvent::Assigned, &audit.actor_id);
+                    data.user_flairs.push(user_flair);
+                }
+            }
+        }
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn count_flaired_users(&self, community_actor_id: String) -> anyhow::Result<u32> {
+        let data = self.read()?;
+
+        let users: HashSet<&str> = data
+            .user_flairs
+            .iter()
+            .filter(|uf| uf.flair_community_actor_id == community_actor_id)
+            .map(|uf| uf.user_actor_id.as_str())
+            .collect();
+
+        Ok(users.len() as u32)
+    }
+
+    async fn get_community_history(
+        &self,
+        community_actor_id: String,
+        limit: u32,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        let data = self.read()?;
+
+        Ok(data
+            .user_flair_history
+            .iter()
+            .rev()
+            .filter(|e| e.community_actor_id == community_actor_id)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn count_daily_assignments(
+        &self,
+        community_actor_id: String,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<HashMap<NaiveDate, u32>> {
+        let data = self.read()?;
+
+        let mut val: HashMap<NaiveDate, u32> = HashMap::new();
+        for e in data.user_flair_history.iter().filter(|e| {
+            e.community_actor_id == community_actor_id && e.event == UserFlairEvent::Assigned
+        }) {
+            let date = e.changed_on.date_naive();
+            if from <= date && date <= to {
+                *val.entry(date).or_default() += 1;
+            }
+        }
+
+        Ok(val)
+    }
+
+    async fn get_user_flair_history(
+        &self,
+        pl: GetUserFlairHistoryJson,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        let data = self.read()?;
+
+        Ok(data
+            .user_flair_history
+            .iter()
+            .rev()
+            .filter(|e| {
+                e.community_actor_id == pl.community_actor_id
+                    && e.user_actor_id == pl.user_actor_id
+                    && pl.before.is_none_or(|b| e.id < b)
+            })
+            .take(pl.limit.unwrap_or(u32::MAX) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_community_list(&self) -> anyhow::Result<Vec<String>> {
+        let data = self.read()?;
+
+        let mut val: Vec<String> = vec![];
+        for flair in &data.flairs {
+            let enabled = data
+                .community_settings
+                .get(&flair.community_actor_id)
+                .is_none_or(|s| s.flairs_enabled);
+            if enabled && !val.contains(&flair.community_actor_id) {
+                val.push(flair.community_actor_id.clone());
+            }
+        }
+
+        Ok(val)
+    }
+
+    async fn get_community_settings(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<CommunitySettings> {
+        let data = self.read()?;
+
+        Ok(match data.community_settings.get(&community_actor_id) {
+            Some(settings) => settings.clone(),
+            None => CommunitySettings::new(community_actor_id),
+        })
+    }
+
+    async fn set_community_settings(
+        &self,
+        settings: CommunitySettings,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.community_settings
+            .insert(settings.community_actor_id.clone(), settings);
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn create_flair_request(
+        &self,
+        pl: AddUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        let mut data = self.write()?;
+
+        data.requests.retain(|r| {
+            !(r.user_actor_id == pl.user_actor_id && r.community_actor_id == pl.community_actor_id)
+        });
+        data.requests.push(FlairRequest {
+            user_actor_id: pl.user_actor_id,
+            community_actor_id: pl.community_actor_id,
+            flair_name: pl.flair_name,
+            custom_text: pl.custom_text,
+            status: RequestStatus::Pending,
+            reason: None,
+            requested_on: Utc::now(),
+            decided_on: None,
+            decided_by: None,
+        });
+        data.record(audit);
+
+        Ok(())
+    }
+
+    async fn get_flair_request(
+        &self,
+        community_actor_id: String,
+        user_actor_id: String,
+    ) -> anyhow::Result<Option<FlairRequest>> {
+        let data = self.read()?;
+
+        Ok(data
+            .requests
+            .iter()
+            .find(|r| {
+                r.user_actor_id == user_actor_id && r.community_actor_id == community_actor_id
+            })
+            .cloned())
+    }
+
+    async fn get_pending_requests(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairRequest>> {
+        let data = self.read()?;
+
+        Ok(data
+            .requests
+            .iter()
+            .filter(|r| {
+                r.community_actor_id == community_actor_id && r.status == RequestStatus::Pending
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn decide_flair_request(
+        &self,
+        pl: DecideFlairRequestJson,
+        replaced: Vec<String>,
+        decided_by: String,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        let mut data = self.write()?;
+
+        let Some(request) = data.requests.iter_mut().find(|r| {
+            r.user_actor_id == pl.user_actor_id
+                && r.community_actor_id == pl.community_actor_id
+                && r.status == RequestStatus::Pending
+        }) else {
+            return Ok(false);
+        };
+
+        request.status = if pl.approved {
+            RequestStatus::Approved
+        } else {
+            RequestStatus::Denied
+        };
+        request.reason = pl.reason;
+        request.decided_on = Some(Utc::now());
+        request.decided_by = Some(decided_by.clone());
+
+        if pl.approved {
+            let user_flair = UserFlair::new(
+                request.user_actor_id.clone(),
+                request.flair_name.clone(),
+                request.community_actor_id.clone(),
+                Utc::now(),
+                request.custom_text.clone(),
+            );
+
+            data.assign(user_flair, &replaced, &decided_by);
+        }
+        data.record(audit);
+
+        Ok(true)
+    }
+
+    async fn get_audit_log(&self, pl: GetAuditLogJson) -> anyhow::Result<Vec<AuditEntry>> {
+        let data = self.read()?;
+
+        Ok(data
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|e| {
+                e.community_actor_id == pl.community_actor_id
+                    && pl.action.is_none_or(|a| e.action == a)
+                    && pl.actor_id.as_ref().is_none_or(|a| &e.actor_id == a)
+                    && pl
+                        .user_actor_id
+                        .as_ref()
+                        .is_none_or(|u| e.user_actor_id.as_ref() == Some(u))
+                    && pl
+                        .flair_name
+                        .as_ref()
+                        .is_none_or(|f| e.flair_name.as_ref() == Some(f))
+                    && pl.before.is_none_or(|b| e.id < b)
+            })
+            .take(pl.limit.unwrap_or(u32::MAX) as usize)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::bail;
+
+use crate::db::FlairStore;
+
+/// Migration is a versioned change to the database structure, written once per backend.
+/// Migrations are embedded in the binary and applied in order, each one only once.
+pub(crate) struct Migration {
+    pub(crate) version: i64,
+    pub(crate) name: &'static str,
+    pub(crate) sqlite: &'static str,
+    pub(crate) postgres: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sqlite: include_str!(concat!(
+                "../../server/sql/migrations/sqlite/",
+                $name,
+                ".sql"
+            )),
+            postgres: include_str!(concat!(
+                "../../server/sql/migrations/postgres/",
+                $name,
+                ".sql"
+            )),
+        }
+    };
+}
+
+/// Every known migration, sorted by version
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial"),
+    migration!(2, "0002_flair_colors"),
+    migration!(3, "0003_editable_text"),
+    migration!(4, "0004_community_settings"),
+    migration!(5, "0005_flair_requests"),
+    migration!(6, "0006_audit_log"),
+    migration!(7, "0007_user_flair_history"),
+    migration!(8, "0008_flair_position"),
+    migration!(9, "0009_flair_groups"),
+    migration!(10, "0010_multiple_flairs"),
+];
+
+/// Statement creating the table which keeps track of the applied migrations, valid for every backend
+pub(crate) const SCHEMA_VERSION_TABLE: &str = r"
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version BIGINT NOT NULL PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_on TEXT NOT NULL
+    );";
+
+/// Latest schema version known by this build of Flair
+pub(crate) fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Returns the current schema version, failing if the database was migrated by a newer build of Flair
+pub(crate) async fn check_version(store: &dyn FlairStore) -> anyhow::Result<i64> {
+    let current = store.schema_version().await?;
+    if current > latest_version() {
+        bail!(
+            "the database schema is at version {}, but this build of flair only knows up to version {}: please upgrade flair",
+            current,
+            latest_version()
+        );
+    }
+
+    Ok(current)
+}
+
+/// Applies every pending migration, returning the ones that were applied
+pub(crate) async fn migrate(store: &dyn FlairStore) -> anyhow::Result<Vec<&'static Migration>> {
+    let current = check_version(store).await?;
+
+    let mut applied = vec![];
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        store.apply_migration(migration).await?;
+        applied.push(migration);
+    }
+
+    Ok(applied)
+}
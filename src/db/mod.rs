@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use flair::{
+    AuditAction, AuditEntry, CommunitySettings, Flair, FlairGroup, FlairMember, FlairRequest,
+    UserFlairHistoryEntry,
+};
+
+use crate::router::AddFlairGroupJson;
+use crate::router::AddFlairJson;
+use crate::router::AddUserFlairJson;
+use crate::router::DecideFlairRequestJson;
+use crate::router::DeleteFlairGroupJson;
+use crate::router::DeleteFlairJson;
+use crate::router::DeleteUserFlairJson;
+use crate::router::GetAuditLogJson;
+use crate::router::GetFlairsJson;
+use crate::router::GetUserFlairHistoryJson;
+use crate::router::GetUserFlairJson;
+use crate::router::ListUserFlairsJson;
+use crate::router::SetUserFlairsJson;
+
+mod memory;
+pub(crate) mod migrations;
+mod postgres;
+mod sqlite;
+
+pub(crate) use memory::MemoryStore;
+pub(crate) use migrations::Migration;
+pub(crate) use postgres::PostgresStore;
+pub(crate) use sqlite::SqliteStore;
+
+/// NewAuditEntry is a change about to be recorded in the audit log, the store assigns its id and timestamp
+#[derive(Debug, Clone)]
+pub(crate) struct NewAuditEntry {
+    pub actor_id: String,
+    pub as_admin: bool,
+    pub action: AuditAction,
+    pub community_actor_id: String,
+    pub user_actor_id: Option<String>,
+    pub flair_name: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+/// FlairStore is the storage layer of Flair. Every backend exposes the same operations,
+/// so that handlers never have to know where flairs are kept.
+/// Writes take the audit entry describing them, recorded within the same transaction as the change.
+#[async_trait]
+pub(crate) trait FlairStore: Send + Sync {
+    /// Human readable name of the backend, used for logging purposes
+    fn backend(&self) -> &'static str;
+
+    /// Version of the latest migration applied to the database, 0 if none was
+    async fn schema_version(&self) -> anyhow::Result<i64>;
+
+    /// Applies a migration and records it in the `schema_version` table, atomically
+    async fn apply_migration(&self, migration: &Migration) -> anyhow::Result<()>;
+
+    /// Fetches the first flair of a user in a community
+    async fn get_user_flair(&self, pl: GetUserFlairJson) -> anyhow::Result<Option<Flair>>;
+
+    /// Lists the flairs of a user in a community, in the order they're displayed
+    async fn get_user_flair_list(&self, pl: GetUserFlairJson) -> anyhow::Result<Vec<Flair>>;
+
+    /// Lists the first flair a user holds in every community, sorted by community.
+    /// `limit` must be set by the caller.
+    async fn list_user_flairs(&self, pl: ListUserFlairsJson) -> anyhow::Result<Vec<Flair>>;
+
+    /// Fetches the flairs of several (user, community) pairs at once.
+    /// The result is grouped by community first and by user second, unflaired users are omitted.
+    /// Each user's flairs are in the order they're displayed.
+    async fn get_user_flairs(
+        &self,
+        pairs: Vec<GetUserFlairJson>,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>>;
+
+    async fn get_flair(
+        &self,
+        community_actor_id: String,
+        name: String,
+    ) -> anyhow::Result<Option<Flair>>;
+
+    /// Lists the flairs of a community, in the order chosen by the mods
+    async fn get_community_flairs(&self, pl: GetFlairsJson) -> anyhow::Result<Vec<Flair>>;
+
+    /// Reorders the flairs of a community, atomically. Returns false, changing nothing,
+    /// unless `names` lists every flair of the community exactly once.
+    async fn reorder_flairs(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool>;
+
+    /// Lists the flair groups of a community, in the order chosen by the mods
+    async fn get_flair_groups(&self, community_actor_id: String)
+        -> anyhow::Result<Vec<FlairGroup>>;
+
+    /// Creates a flair group, or updates it if a group with the same name exists in the community.
+    /// New groups are placed after the existing ones.
+    async fn add_flair_group(
+        &self,
+        pl: AddFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Deletes a flair group, its flairs are kept but no longer belong to any group
+    async fn delete_flair_group(
+        &self,
+        pl: DeleteFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Reorders the flair groups of a community, atomically. Returns false, changing nothing,
+    /// unless `names` lists every group of the community exactly once.
+    async fn reorder_flair_groups(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool>;
+
+    /// Counts the users holding each flair of a community, flairs nobody holds are omitted
+    async fn count_flair_users(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<HashMap<String, u32>>;
+
+    /// Lists the users holding a flair, most recently assigned first.
+    /// Only the users assigned before the `before` (assigned_on, user_actor_id) pair are returned, if set.
+    async fn get_flair_members(
+        &self,
+        community_actor_id: String,
+        flair_name: String,
+        before: Option<(DateTime<Utc>, String)>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<FlairMember>>;
+
+    /// Creates a flair, or updates it if a flair with the same name exists in the community.
+    /// New flairs are placed after the existing ones.
+    /// Making the flair's text not editable clears the custom text of the users holding it.
+    async fn add_flair(&self, pl: AddFlairJson, audit: NewAuditEntry) -> anyhow::Result<()>;
+
+    /// Deletes a flair, removing it from every user holding it.
+    /// The removals are recorded in the users' flair history.
+    async fn delete_flair(&self, pl: DeleteFlairJson, audit: NewAuditEntry) -> anyhow::Result<()>;
+
+    /// Assigns a flair to a user in place of the `replaced` flairs, keeping the other ones they hold in the community.
+    /// The flair keeps its place if the user already held it, otherwise it takes the place of the first replaced one
+    /// or goes after the others. The assignment is recorded in the user's flair history.
+    async fn assign_user_flair(
+        &self,
+        pl: AddUserFlairJson,
+        replaced: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Removes every flair of a user in a community, recording the removals in their flair history
+    async fn delete_user_flair(
+        &self,
+        pl: DeleteUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Replaces the flairs of a user in a community with the listed ones, in that order, atomically.
+    /// Flairs the user keeps with the same text aren't assigned again, every other change
+    /// is recorded in the user's flair history.
+    async fn set_user_flairs(
+        &self,
+        pl: SetUserFlairsJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Counts the users holding at least one flair in a community
+    async fn count_flaired_users(&self, community_actor_id: String) -> anyhow::Result<u32>;
+
+    /// Lists the flairs users received or lost in a community, newest first
+    async fn get_community_history(
+        &self,
+        community_actor_id: String,
+        limit: u32,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>>;
+
+    /// Counts the flairs assigned in a community on each day between `from` and `to` (UTC, both included).
+    /// Days without assignments are omitted.
+    async fn count_daily_assignments(
+        &self,
+        community_actor_id: String,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<HashMap<NaiveDate, u32>>;
+
+    /// Lists the flairs a user received or lost in a community, newest first.
+    /// `limit` must be set by the caller.
+    async fn get_user_flair_history(
+        &self,
+        pl: GetUserFlairHistoryJson,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>>;
+
+    /// Lists the communities with at least one flair, unless flairs were disabled by the mods
+    async fn get_community_list(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Fetches the settings of a community, falling back to the defaults if mods never changed them
+    async fn get_community_settings(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<CommunitySettings>;
+
+    /// Creates or replaces the settings of a community
+    async fn set_community_settings(
+        &self,
+        settings: CommunitySettings,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Submits a flair request for the mods to review, replacing the previous request of the user in the community
+    async fn create_flair_request(
+        &self,
+        pl: AddUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()>;
+
+    /// Fetches the latest request of a user in a community, whatever its status
+    async fn get_flair_request(
+        &self,
+        community_actor_id: String,
+        user_actor_id: String,
+    ) -> anyhow::Result<Option<FlairRequest>>;
+
+    /// Lists the requests of a community waiting for a decision, oldest first
+    async fn get_pending_requests(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairRequest>>;
+
+    /// Approves or denies a pending request, approving it assigns the flair within the same transaction
+    /// in place of the `replaced` flairs, like [`FlairStore::assign_user_flair`] does.
+    /// Returns false if the user had no pending request in the community, nothing is recorded then.
+    async fn decide_flair_request(
+        &self,
+        pl: DecideFlairRequestJson,
+        replaced: Vec<String>,
+        decided_by: String,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool>;
+
+    /// Lists the audit log of a community, newest first, applying the filters of the payload.
+    /// `limit` must be set by the caller.
+    async fn get_audit_log(&self, pl: GetAuditLogJson) -> anyhow::Result<Vec<AuditEntry>>;
+}
+
+/// Parses a before/after value of the audit log, stored as a JSON string by every backend
+fn parse_audit_value(value: Option<String>) -> anyhow::Result<Option<serde_json::Value>> {
+    Ok(value.as_deref().map(serde_json::from_str).transpose()?)
+}
+
+/// Parses a timestamp, stored as an RFC 3339 string by every backend
+fn parse_timestamp(timestamp: &str) -> anyhow::Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(timestamp)?.with_timezone(&Utc))
+}
+
+/// Picks the storage backend from the `FLAIR_DB_URL` environment variable:
+/// - **Postgres** if the url starts with `postgres://` or `postgresql://`
+/// - **In memory** if the url is `:memory:`, nothing is persisted (useful for testing)
+/// - **SQLite** otherwise, the url being the path of the database file
+pub(crate) fn connect(url: &str) -> anyhow::Result<Arc<dyn FlairStore>> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(PostgresStore::connect(url)?))
+    } else if url == ":memory:" {
+        Ok(Arc::new(MemoryStore::default()))
+    } else {
+        Ok(Arc::new(SqliteStore::connect(url)?))
+    }
+}
+
+#[cfg(test)]
+mod tests;
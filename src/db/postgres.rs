@@ -0,0 +1,1502 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_postgres::{Client, Manager, ManagerConfig, Pool, RecyclingMethod, Transaction};
+use tokio_postgres::{NoTls, Row};
+
+use flair::{
+    AuditEntry, CommunitySettings, Flair, FlairGroup, FlairMember, FlairRequest, RequestStatus,
+    UserFlairEvent, UserFlairHistoryEntry,
+};
+
+use crate::db::migrations::SCHEMA_VERSION_TABLE;
+use crate::db::{parse_audit_value, parse_timestamp, FlairStore, Migration, NewAuditEntry};
+use crate::router::AddFlairGroupJson;
+use crate::router::AddFlairJson;
+use crate::router::AddUserFlairJson;
+use crate::router::DecideFlairRequestJson;
+use crate::router::DeleteFlairGroupJson;
+use crate::router::DeleteFlairJson;
+use crate::router::DeleteUserFlairJson;
+use crate::router::GetAuditLogJson;
+use crate::router::GetFlairsJson;
+use crate::router::GetUserFlairHistoryJson;
+use crate::router::GetUserFlairJson;
+use crate::router::ListUserFlairsJson;
+use crate::router::SetUserFlairsJson;
+
+/// PostgresStore keeps flairs in a Postgres database, possibly the same cluster used by Lemmy
+pub(crate) struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub(crate) fn connect(url: &str) -> anyhow::Result<Self> {
+        let pg_config = tokio_postgres::Config::from_str(url)?;
+        let manager = Manager::from_config(
+            pg_config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager).build()?;
+
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl FlairStore for PostgresStore {
+    fn backend(&self) -> &'static str {
+        "Postgres"
+    }
+
+    async fn schema_version(&self) -> anyhow::Result<i64> {
+        let client = self.pool.get().await?;
+
+        // Checking beforehand avoids the notice Postgres logs when the table already exists
+        let exists = client
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])
+            .await?;
+        if !exists.try_get::<_, bool>(0)? {
+            client.batch_execute(SCHEMA_VERSION_TABLE).await?;
+        }
+
+        let row = client
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0)::BIGINT FROM schema_version",
+                &[],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> anyhow::Result<()> {
+        let mut client = self.pool.get().await?;
+
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.postgres).await?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_on) VALUES ($1, $2, $3)",
+            &[
+                &migration.version,
+                &migration.name,
+                &Utc::now().to_rfc3339(),
+            ],
+        )
+        .await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    async fn get_user_flair(&self, pl: GetUserFlairJson) -> anyhow::Result<Option<Flair>> {
+        get_user_flair(&self.pool.get().await?, &pl).await
+    }
+
+    async fn get_user_flair_list(&self, pl: GetUserFlairJson) -> anyhow::Result<Vec<Flair>> {
+        get_user_flair_list(&self.pool.get().await?, &pl).await
+    }
+
+    async fn list_user_flairs(&self, pl: ListUserFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        list_user_flairs(&self.pool.get().await?, &pl).await
+    }
+
+    async fn get_user_flairs(
+        &self,
+        pairs: Vec<GetUserFlairJson>,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>> {
+        get_user_flairs(&self.pool.get().await?, &pairs).await
+    }
+
+    async fn get_flair(
+        &self,
+        community_actor_id: String,
+        name: String,
+    ) -> anyhow::Result<Option<Flair>> {
+        get_flair(&self.pool.get().await?, &community_actor_id, &name).await
+    }
+
+    async fn get_community_flairs(&self, pl: GetFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        get_community_flairs(&self.pool.get().await?, &pl).await
+    }
+
+    async fn reorder_flairs(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        reorder_flairs(
+            &mut self.pool.get().await?,
+            &community_actor_id,
+            &names,
+            &audit,
+        )
+        .await
+    }
+
+    async fn get_flair_groups(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairGroup>> {
+        get_flair_groups(&self.pool.get().await?, &community_actor_id).await
+    }
+
+    async fn add_flair_group(
+        &self,
+        pl: AddFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        add_flair_group(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn delete_flair_group(
+        &self,
+        pl: DeleteFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        delete_flair_group(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn reorder_flair_groups(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        reorder_flair_groups(
+            &mut self.pool.get().await?,
+            &community_actor_id,
+            &names,
+            &audit,
+        )
+        .await
+    }
+
+    async fn count_flair_users(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<HashMap<String, u32>> {
+        count_flair_users(&self.pool.get().await?, &community_actor_id).await
+    }
+
+    async fn get_flair_members(
+        &self,
+        community_actor_id: String,
+        flair_name: String,
+        before: Option<(DateTime<Utc>, String)>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<FlairMember>> {
+        get_flair_members(
+            &self.pool.get().await?,
+            &community_actor_id,
+            &flair_name,
+            &before,
+            limit,
+        )
+        .await
+    }
+
+    async fn add_flair(&self, pl: AddFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        add_flair(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn delete_flair(&self, pl: DeleteFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        delete_flair(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn assign_user_flair(
+        &self,
+        pl: AddUserFlairJson,
+        replaced: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        assign_user_flair(&mut self.pool.get().await?, &pl, &replaced, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn delete_user_flair(
+        &self,
+        pl: DeleteUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        delete_user_flair(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn set_user_flairs(
+        &self,
+        pl: SetUserFlairsJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        set_user_flairs(&mut self.pool.get().await?, &pl, &audit).await
+    }
+
+    async fn count_flaired_users(&self, community_actor_id: String) -> anyhow::Result<u32> {
+        count_flaired_users(&self.pool.get().await?, &community_actor_id).await
+    }
+
+    async fn get_community_history(
+        &self,
+        community_actor_id: String,
+        limit: u32,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        get_community_history(&self.pool.get().await?, &community_actor_id, limit).await
+    }
+
+    async fn count_daily_assignments(
+        &self,
+        community_actor_id: String,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<HashMap<NaiveDate, u32>> {
+        count_daily_assignments(&self.pool.get().await?, &community_actor_id, from, to).await
+    }
+
+    async fn get_user_flair_history(
+        &self,
+        pl: GetUserFlairHistoryJson,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        get_user_flair_history(&self.pool.get().await?, &pl).await
+    }
+
+    async fn get_community_list(&self) -> anyhow::Result<Vec<String>> {
+        get_community_list(&self.pool.get().await?).await
+    }
+
+    async fn get_community_settings(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<CommunitySettings> {
+        get_community_settings(&self.pool.get().await?, community_actor_id).await
+    }
+
+    async fn set_community_settings(
+        &self,
+        settings: CommunitySettings,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        set_community_settings(&mut self.pool.get().await?, &settings, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn create_flair_request(
+        &self,
+        pl: AddUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        create_flair_request(&mut self.pool.get().await?, &pl, &audit).await?;
+
+        Ok(())
+    }
+
+    async fn get_flair_request(
+        &self,
+        community_actor_id: String,
+        user_actor_id: String,
+    ) -> anyhow::Result<Option<FlairRequest>> {
+        get_flair_request(&self.pool.get().await?, &community_actor_id, &user_actor_id).await
+    }
+
+    async fn get_pending_requests(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairRequest>> {
+        get_pending_requests(&self.pool.get().await?, &community_actor_id).await
+    }
+
+    async fn decide_flair_request(
+        &self,
+        pl: DecideFlairRequestJson,
+        replaced: Vec<String>,
+        decided_by: String,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        decide_flair_request(
+            &mut self.pool.get().await?,
+            &pl,
+            &replaced,
+            &decided_by,
+            &audit,
+        )
+        .await
+    }
+
+    async fn get_audit_log(&self, pl: GetAuditLogJson) -> anyhow::Result<Vec<AuditEntry>> {
+        get_audit_log(&self.pool.get().await?, &pl).await
+    }
+}
+
+fn flair_from_row(r: &Row) -> anyhow::Result<Flair> {
+    Ok(Flair::new(
+        r.try_get(0)?,
+        r.try_get(1)?,
+        r.try_get(2)?,
+        r.try_get(3)?,
+        r.try_get(4)?,
+        r.try_get(5)?,
+        r.try_get(6)?,
+        r.try_get(7)?,
+        r.try_get::<_, Option<i32>>(8)?.map(|l| l as u32),
+        r.try_get(9)?,
+    ))
+}
+
+pub(crate) async fn get_user_flair(
+    client: &Client,
+    pl: &GetUserFlairJson,
+) -> anyhow::Result<Option<Flair>> {
+    Ok(get_user_flair_list(client, pl).await?.into_iter().next())
+}
+
+pub(crate) async fn get_user_flair_list(
+    client: &Client,
+    pl: &GetUserFlairJson,
+) -> anyhow::Result<Vec<Flair>> {
+    let stmt = client.prepare_cached("
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE f.community_actor_id = $1 AND uf.user_actor_id = $2
+            ORDER BY uf.position;
+        ",
+    ).await?;
+
+    client
+        .query(&stmt, &[&pl.community_actor_id, &pl.user_actor_id])
+        .await?
+        .iter()
+        .map(flair_from_row)
+        .collect()
+}
+
+pub(crate) async fn list_user_flairs(
+    client: &Client,
+    pl: &ListUserFlairsJson,
+) -> anyhow::Result<Vec<Flair>> {
+    let stmt = client.prepare_cached("
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE uf.user_actor_id = $1 AND ($2::TEXT IS NULL OR uf.flair_community_actor_id > $2)
+                AND uf.position = (
+                    SELECT MIN(position) FROM user_flairs first
+                    WHERE first.user_actor_id = uf.user_actor_id AND first.flair_community_actor_id = uf.flair_community_actor_id
+                )
+            ORDER BY uf.flair_community_actor_id
+            LIMIT $3
+        ",
+    ).await?;
+
+    client
+        .query(
+            &stmt,
+            &[&pl.user_actor_id, &pl.after, &pl.limit.map(i64::from)],
+        )
+        .await?
+        .iter()
+        .map(flair_from_row)
+        .collect()
+}
+
+/// Fetches the flairs of several (user, community) pairs in a single query.
+pub(crate) async fn get_user_flairs(
+    client: &Client,
+    pairs: &[GetUserFlairJson],
+) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>> {
+    let mut val: HashMap<String, HashMap<String, Vec<Flair>>> = HashMap::new();
+    if pairs.is_empty() {
+        return Ok(val);
+    }
+
+    let stmt = client.prepare_cached("
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name, uf.user_actor_id
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE (uf.user_actor_id, uf.flair_community_actor_id) IN (SELECT * FROM unnest($1::text[], $2::text[]))
+            ORDER BY uf.position;
+        ",
+    ).await?;
+
+    let users: Vec<&str> = pairs.iter().map(|p| p.user_actor_id.as_str()).collect();
+    let communities: Vec<&str> = pairs
+        .iter()
+        .map(|p| p.community_actor_id.as_str())
+        .collect();
+
+    for r in client.query(&stmt, &[&users, &communities]).await? {
+        let flair = flair_from_row(&r)?;
+
+        val.entry(flair.community_actor_id.clone())
+            .or_default()
+            .entry(r.try_get(10)?)
+            .or_default()
+            .push(flair);
+    }
+
+    Ok(val)
+}
+
+pub(crate) async fn get_community_flairs(
+    client: &Client,
+    pl: &GetFlairsJson,
+) -> anyhow::Result<Vec<Flair>> {
+    //If mod only == true display both non mod and mod flairs
+    //If mod only == false display only non mod flairs
+    let stmt = client
+        .prepare_cached(
+            "SELECT name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name
+            FROM flairs
+            WHERE community_actor_id = $1 and mod_only <= $2
+            ORDER BY position, name
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[&pl.community_actor_id, &pl.mod_only.unwrap_or(false)],
+        )
+        .await?
+        .iter()
+        .map(flair_from_row)
+        .collect()
+}
+
+pub(crate) async fn get_flair(
+    client: &Client,
+    community_actor_id: &str,
+    name: &str,
+) -> anyhow::Result<Option<Flair>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name
+            FROM flairs
+            WHERE community_actor_id = $1 AND name = $2
+        ",
+        )
+        .await?;
+
+    match client
+        .query_opt(&stmt, &[&community_actor_id, &name])
+        .await?
+    {
+        Some(r) => Ok(Some(flair_from_row(&r)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) async fn reorder_flairs(
+    client: &mut Client,
+    community_actor_id: &str,
+    names: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let tx = client.transaction().await?;
+
+    // Locking the flairs keeps them from being deleted while they're being reordered
+    let count = tx
+        .query(
+            "SELECT name FROM flairs WHERE community_actor_id = $1 FOR UPDATE",
+            &[&community_actor_id],
+        )
+        .await?
+        .len();
+    if count != names.len() {
+        return Ok(false);
+    }
+
+    for (position, name) in names.iter().enumerate() {
+        let updated = tx
+            .execute(
+                r"UPDATE flairs SET position = $1 WHERE community_actor_id = $2 AND name = $3",
+                &[&(position as i32), &community_actor_id, name],
+            )
+            .await?;
+        if updated == 0 {
+            return Ok(false);
+        }
+    }
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub(crate) async fn get_flair_groups(
+    client: &Client,
+    community_actor_id: &str,
+) -> anyhow::Result<Vec<FlairGroup>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT name, display_name, community_actor_id, pick_one
+            FROM flair_groups
+            WHERE community_actor_id = $1
+            ORDER BY position, name
+        ",
+        )
+        .await?;
+
+    let rows = client.query(&stmt, &[&community_actor_id]).await?;
+
+    let mut val: Vec<FlairGroup> = vec![];
+    for r in rows {
+        val.push(FlairGroup {
+            name: r.try_get(0)?,
+            display_name: r.try_get(1)?,
+            community_actor_id: r.try_get(2)?,
+            pick_one: r.try_get(3)?,
+        });
+    }
+
+    Ok(val)
+}
+
+pub(crate) async fn add_flair_group(
+    client: &mut Client,
+    pl: &AddFlairGroupJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    let result = tx
+        .execute(
+            r"INSERT INTO flair_groups (name, community_actor_id, display_name, pick_one, position)
+            VALUES ($1, $2, $3, $4, (SELECT COALESCE(MAX(position) + 1, 0) FROM flair_groups WHERE community_actor_id = $2))
+            ON CONFLICT (name, community_actor_id) DO UPDATE
+            SET display_name = EXCLUDED.display_name, pick_one = EXCLUDED.pick_one
+            ",
+            &[
+                &pl.name,
+                &pl.community_actor_id,
+                &pl.display_name,
+                &pl.pick_one.unwrap_or(false),
+            ],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn delete_flair_group(
+    client: &mut Client,
+    pl: &DeleteFlairGroupJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    tx.execute(
+        r"UPDATE flairs SET group_name = NULL WHERE group_name = $1 AND community_actor_id = $2",
+        &[&pl.name, &pl.community_actor_id],
+    )
+    .await?;
+
+    let result = tx
+        .execute(
+            r"DELETE FROM flair_groups WHERE name = $1 AND community_actor_id = $2",
+            &[&pl.name, &pl.community_actor_id],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn reorder_flair_groups(
+    client: &mut Client,
+    community_actor_id: &str,
+    names: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let tx = client.transaction().await?;
+
+    // Locking the groups keeps them from being deleted while they're being reordered
+    let count = tx
+        .query(
+            "SELECT name FROM flair_groups WHERE community_actor_id = $1 FOR UPDATE",
+            &[&community_actor_id],
+        )
+        .await?
+        .len();
+    if count != names.len() {
+        return Ok(false);
+    }
+
+    for (position, name) in names.iter().enumerate() {
+        let updated = tx
+            .execute(
+                r"UPDATE flair_groups SET position = $1 WHERE community_actor_id = $2 AND name = $3",
+                &[&(position as i32), &community_actor_id, name],
+            )
+            .await?;
+        if updated == 0 {
+            return Ok(false);
+        }
+    }
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub(crate) async fn count_flair_users(
+    client: &Client,
+    community_actor_id: &str,
+) -> anyhow::Result<HashMap<String, u32>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT flair_name, COUNT(*)
+            FROM user_flairs
+            WHERE flair_community_actor_id = $1
+            GROUP BY flair_name
+        ",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&community_actor_id])
+        .await?
+        .iter()
+        .map(|r| Ok((r.try_get(0)?, r.try_get::<_, i64>(1)? as u32)))
+        .collect()
+}
+
+pub(crate) async fn get_flair_members(
+    client: &Client,
+    community_actor_id: &str,
+    flair_name: &str,
+    before: &Option<(DateTime<Utc>, String)>,
+    limit: u32,
+) -> anyhow::Result<Vec<FlairMember>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT user_actor_id, custom_text, assigned_on
+            FROM user_flairs
+            WHERE flair_community_actor_id = $1 AND flair_name = $2
+                AND ($3::TEXT IS NULL OR (assigned_on, user_actor_id) < ($3, $4::TEXT))
+            ORDER BY assigned_on DESC, user_actor_id DESC
+            LIMIT $5
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[
+                &community_actor_id,
+                &flair_name,
+                &before.as_ref().map(|(date, _)| date.to_rfc3339()),
+                &before.as_ref().map(|(_, user)| user),
+                &i64::from(limit),
+            ],
+        )
+        .await?
+        .iter()
+        .map(|r| {
+            let assigned_on: String = r.try_get(2)?;
+
+            Ok(FlairMember {
+                user_actor_id: r.try_get(0)?,
+                custom_text: r.try_get(1)?,
+                assigned_on: parse_timestamp(&assigned_on)?,
+            })
+        })
+        .collect()
+}
+
+pub(crate) async fn add_flair(
+    client: &mut Client,
+    pl: &AddFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    let result = tx
+        .execute(
+            r"INSERT INTO flairs (name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name, position)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, (SELECT COALESCE(MAX(position) + 1, 0) FROM flairs WHERE community_actor_id = $4))
+            ON CONFLICT (name, community_actor_id) DO UPDATE
+            SET display_name = EXCLUDED.display_name, path = EXCLUDED.path, mod_only = EXCLUDED.mod_only,
+                background_color = EXCLUDED.background_color, text_color = EXCLUDED.text_color,
+                text_editable = EXCLUDED.text_editable, max_length = EXCLUDED.max_length, group_name = EXCLUDED.group_name
+            ",
+            &[
+                &pl.name,
+                &pl.display_name,
+                &pl.path,
+                &pl.community_actor_id,
+                &pl.mod_only,
+                &pl.background_color,
+                &pl.text_color,
+                &pl.text_editable.unwrap_or(false),
+                &pl.max_length.map(|l| l as i32),
+                &pl.group_name,
+            ],
+        )
+        .await?;
+
+    // Locking the text brings every user back to the flair's display name
+    if !pl.text_editable.unwrap_or(false) {
+        tx.execute(
+            r"UPDATE user_flairs SET custom_text = NULL WHERE flair_name = $1 AND flair_community_actor_id = $2",
+            &[&pl.name, &pl.community_actor_id],
+        )
+        .await?;
+    }
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn delete_flair(
+    client: &mut Client,
+    pl: &DeleteFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    // The flair's users are removed by the foreign key, their flairs are recorded beforehand
+    tx.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            SELECT user_actor_id, flair_community_actor_id, flair_name, custom_text, $1, $2, $3
+            FROM user_flairs
+            WHERE flair_name = $4 AND flair_community_actor_id = $5
+            ",
+        &[
+            &UserFlairEvent::Removed.as_str(),
+            &audit.actor_id,
+            &Utc::now().to_rfc3339(),
+            &pl.name,
+            &pl.community_actor_id,
+        ],
+    )
+    .await?;
+
+    let result = tx
+        .execute(
+            r"DELETE FROM flairs WHERE name = $1 AND community_actor_id = $2",
+            &[&pl.name, &pl.community_actor_id],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn assign_user_flair(
+    client: &mut Client,
+    pl: &AddUserFlairJson,
+    replaced: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    let result = place_user_flair(
+        &tx,
+        &pl.user_actor_id,
+        &pl.community_actor_id,
+        &pl.flair_name,
+        &pl.custom_text,
+        replaced,
+        &audit.actor_id,
+    )
+    .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+/// Saves a flair of a user in place of the replaced ones and records the assignment in their flair history
+async fn place_user_flair(
+    tx: &Transaction<'_>,
+    user_actor_id: &str,
+    community_actor_id: &str,
+    flair_name: &str,
+    custom_text: &Option<String>,
+    replaced: &[String],
+    assigned_by: &str,
+) -> anyhow::Result<u64> {
+    let now = Utc::now().to_rfc3339();
+
+    // The flair keeps its place or takes the one of the first flair it replaces, otherwise it goes last.
+    // The user's flairs stay locked until the new one is saved
+    let mut position: Option<i32> = None;
+    let mut last: i32 = -1;
+    for r in tx
+        .query(
+            "SELECT flair_name, position
+            FROM user_flairs
+            WHERE user_actor_id = $1 AND flair_community_actor_id = $2
+            ORDER BY position
+            FOR UPDATE
+            ",
+            &[&user_actor_id, &community_actor_id],
+        )
+        .await?
+    {
+        let name: String = r.try_get(0)?;
+        last = r.try_get(1)?;
+        if name == flair_name || replaced.contains(&name) {
+            position.get_or_insert(last);
+        }
+    }
+
+    let delete = tx
+        .prepare(
+            r"DELETE FROM user_flairs WHERE user_actor_id = $1 AND flair_community_actor_id = $2 AND flair_name = $3",
+        )
+        .await?;
+    for name in replaced.iter().map(String::as_str).chain([flair_name]) {
+        tx.execute(&delete, &[&user_actor_id, &community_actor_id, &name])
+            .await?;
+    }
+
+    let result = tx
+        .execute(
+            r"INSERT INTO user_flairs (user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text, position)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ",
+            &[
+                &user_actor_id,
+                &flair_name,
+                &community_actor_id,
+                &now,
+                custom_text,
+                &position.unwrap_or(last + 1),
+            ],
+        )
+        .await?;
+
+    tx.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ",
+        &[
+            &user_actor_id,
+            &community_actor_id,
+            &flair_name,
+            custom_text,
+            &UserFlairEvent::Assigned.as_str(),
+            &assigned_by,
+            &now,
+        ],
+    )
+    .await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn delete_user_flair(
+    client: &mut Client,
+    pl: &DeleteUserFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    tx.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            SELECT user_actor_id, flair_community_actor_id, flair_name, custom_text, $1, $2, $3
+            FROM user_flairs
+            WHERE user_actor_id = $4 AND flair_community_actor_id = $5
+            ",
+        &[
+            &UserFlairEvent::Removed.as_str(),
+            &audit.actor_id,
+            &Utc::now().to_rfc3339(),
+            &pl.user_actor_id,
+            &pl.community_actor_id,
+        ],
+    )
+    .await?;
+
+    let result = tx
+        .execute(
+            r"DELETE FROM user_flairs WHERE user_actor_id = $1 AND flair_community_actor_id = $2",
+            &[&pl.user_actor_id, &pl.community_actor_id],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn set_user_flairs(
+    client: &mut Client,
+    pl: &SetUserFlairsJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<()> {
+    let now = Utc::now().to_rfc3339();
+
+    let tx = client.transaction().await?;
+
+    // Flair name -> (assigned_on, custom_text) of the flairs the user holds, locked until the new ones are saved
+    let mut current: BTreeMap<String, (String, Option<String>)> = BTreeMap::new();
+    for r in tx
+        .query(
+            "SELECT flair_name, assigned_on, custom_text
+            FROM user_flairs
+            WHERE user_actor_id = $1 AND flair_community_actor_id = $2
+            FOR UPDATE
+            ",
+            &[&pl.user_actor_id, &pl.community_actor_id],
+        )
+        .await?
+    {
+        current.insert(r.try_get(0)?, (r.try_get(1)?, r.try_get(2)?));
+    }
+
+    tx.execute(
+        r"DELETE FROM user_flairs WHERE user_actor_id = $1 AND flair_community_actor_id = $2",
+        &[&pl.user_actor_id, &pl.community_actor_id],
+    )
+    .await?;
+
+    let history = tx
+        .prepare(
+            r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ",
+        )
+        .await?;
+
+    for (flair_name, (_, custom_text)) in &current {
+        if !pl.flairs.iter().any(|f| &f.flair_name == flair_name) {
+            tx.execute(
+                &history,
+                &[
+                    &pl.user_actor_id,
+                    &pl.community_actor_id,
+                    flair_name,
+                    custom_text,
+                    &UserFlairEvent::Removed.as_str(),
+                    &audit.actor_id,
+                    &now,
+                ],
+            )
+            .await?;
+        }
+    }
+
+    for (position, choice) in pl.flairs.iter().enumerate() {
+        let kept = current
+            .get(&choice.flair_name)
+            .filter(|(_, custom_text)| custom_text == &choice.custom_text);
+
+        tx.execute(
+            r"INSERT INTO user_flairs (user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text, position)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ",
+            &[
+                &pl.user_actor_id,
+                &choice.flair_name,
+                &pl.community_actor_id,
+                kept.map_or(&now, |(assigned_on, _)| assigned_on),
+                &choice.custom_text,
+                &(position as i32),
+            ],
+        )
+        .await?;
+
+        if kept.is_none() {
+            tx.execute(
+                &history,
+                &[
+                    &pl.user_actor_id,
+                    &pl.community_actor_id,
+                    &choice.flair_name,
+                    &choice.custom_text,
+                    &UserFlairEvent::Assigned.as_str(),
+                    &audit.actor_id,
+                    &now,
+                ],
+            )
+            .await?;
+        }
+    }
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub(crate) async fn count_flaired_users(
+    client: &Client,
+    community_actor_id: &str,
+) -> anyhow::Result<u32> {
+    let row = client
+        .query_one(
+            "SELECT COUNT(DISTINCT user_actor_id) FROM user_flairs WHERE flair_community_actor_id = $1",
+            &[&community_actor_id],
+        )
+        .await?;
+
+    Ok(row.try_get::<_, i64>(0)? as u32)
+}
+
+fn history_entry_from_row(r: &Row) -> anyhow::Result<UserFlairHistoryEntry> {
+    let event: String = r.try_get(5)?;
+    let changed_on: String = r.try_get(7)?;
+
+    Ok(UserFlairHistoryEntry {
+        id: r.try_get(0)?,
+        user_actor_id: r.try_get(1)?,
+        community_actor_id: r.try_get(2)?,
+        flair_name: r.try_get(3)?,
+        custom_text: r.try_get(4)?,
+        event: event.parse().map_err(|e: String| anyhow!(e))?,
+        changed_by: r.try_get(6)?,
+        changed_on: parse_timestamp(&changed_on)?,
+    })
+}
+
+pub(crate) async fn get_community_history(
+    client: &Client,
+    community_actor_id: &str,
+    limit: u32,
+) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT id, user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on
+            FROM user_flair_history
+            WHERE community_actor_id = $1
+            ORDER BY id DESC
+            LIMIT $2
+        ",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&community_actor_id, &i64::from(limit)])
+        .await?
+        .iter()
+        .map(history_entry_from_row)
+        .collect()
+}
+
+pub(crate) async fn count_daily_assignments(
+    client: &Client,
+    community_actor_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> anyhow::Result<HashMap<NaiveDate, u32>> {
+    // Timestamps are stored in UTC, their first 10 characters being the date
+    let stmt = client
+        .prepare_cached(
+            "SELECT substr(changed_on, 1, 10), COUNT(*)
+            FROM user_flair_history
+            WHERE community_actor_id = $1 AND event = $2 AND substr(changed_on, 1, 10) BETWEEN $3 AND $4
+            GROUP BY substr(changed_on, 1, 10)
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[
+                &community_actor_id,
+                &UserFlairEvent::Assigned.as_str(),
+                &from.to_string(),
+                &to.to_string(),
+            ],
+        )
+        .await?
+        .iter()
+        .map(|r| {
+            let date: String = r.try_get(0)?;
+
+            Ok((date.parse()?, r.try_get::<_, i64>(1)? as u32))
+        })
+        .collect()
+}
+
+pub(crate) async fn get_user_flair_history(
+    client: &Client,
+    pl: &GetUserFlairHistoryJson,
+) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT id, user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on
+            FROM user_flair_history
+            WHERE community_actor_id = $1 AND user_actor_id = $2
+                AND ($3::BIGINT IS NULL OR id < $3)
+            ORDER BY id DESC
+            LIMIT $4
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[
+                &pl.community_actor_id,
+                &pl.user_actor_id,
+                &pl.before,
+                &pl.limit.map(i64::from),
+            ],
+        )
+        .await?
+        .iter()
+        .map(history_entry_from_row)
+        .collect()
+}
+
+pub(crate) async fn get_community_list(client: &Client) -> anyhow::Result<Vec<String>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT f.community_actor_id
+            FROM flairs f
+            LEFT JOIN community_settings s ON f.community_actor_id = s.community_actor_id
+            WHERE COALESCE(s.flairs_enabled, TRUE)
+            GROUP BY f.community_actor_id
+        ",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[])
+        .await?
+        .iter()
+        .map(|r| Ok(r.try_get(0)?))
+        .collect()
+}
+
+pub(crate) async fn get_community_settings(
+    client: &Client,
+    community_actor_id: String,
+) -> anyhow::Result<CommunitySettings> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT flairs_enabled, self_assign, self_remove, require_approval, max_flairs
+            FROM community_settings
+            WHERE community_actor_id = $1
+        ",
+        )
+        .await?;
+
+    match client.query_opt(&stmt, &[&community_actor_id]).await? {
+        Some(r) => Ok(CommunitySettings {
+            flairs_enabled: r.try_get(0)?,
+            self_assign: r.try_get(1)?,
+            self_remove: r.try_get(2)?,
+            require_approval: r.try_get(3)?,
+            max_flairs: r.try_get::<_, i32>(4)? as u32,
+            community_actor_id,
+        }),
+        None => Ok(CommunitySettings::new(community_actor_id)),
+    }
+}
+
+pub(crate) async fn set_community_settings(
+    client: &mut Client,
+    settings: &CommunitySettings,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    let result = tx
+        .execute(
+            r"INSERT INTO community_settings (community_actor_id, flairs_enabled, self_assign, self_remove, require_approval, max_flairs)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (community_actor_id) DO UPDATE
+            SET flairs_enabled = EXCLUDED.flairs_enabled, self_assign = EXCLUDED.self_assign,
+                self_remove = EXCLUDED.self_remove, require_approval = EXCLUDED.require_approval,
+                max_flairs = EXCLUDED.max_flairs
+            ",
+            &[
+                &settings.community_actor_id,
+                &settings.flairs_enabled,
+                &settings.self_assign,
+                &settings.self_remove,
+                &settings.require_approval,
+                &(settings.max_flairs as i32),
+            ],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+fn request_from_row(r: &Row) -> anyhow::Result<FlairRequest> {
+    let status: String = r.try_get(4)?;
+    let requested_on: String = r.try_get(6)?;
+    let decided_on: Option<String> = r.try_get(7)?;
+
+    Ok(FlairRequest {
+        user_actor_id: r.try_get(0)?,
+        community_actor_id: r.try_get(1)?,
+        flair_name: r.try_get(2)?,
+        custom_text: r.try_get(3)?,
+        status: status.parse().map_err(|e: String| anyhow!(e))?,
+        reason: r.try_get(5)?,
+        requested_on: parse_timestamp(&requested_on)?,
+        decided_on: decided_on.as_deref().map(parse_timestamp).transpose()?,
+        decided_by: r.try_get(8)?,
+    })
+}
+
+pub(crate) async fn create_flair_request(
+    client: &mut Client,
+    pl: &AddUserFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<u64> {
+    let tx = client.transaction().await?;
+
+    let result = tx
+        .execute(
+            r"INSERT INTO flair_requests (user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by)
+            VALUES ($1, $2, $3, $4, $5, NULL, $6, NULL, NULL)
+            ON CONFLICT (user_actor_id, community_actor_id) DO UPDATE
+            SET flair_name = EXCLUDED.flair_name, custom_text = EXCLUDED.custom_text, status = EXCLUDED.status,
+                reason = NULL, requested_on = EXCLUDED.requested_on, decided_on = NULL, decided_by = NULL
+            ",
+            &[
+                &pl.user_actor_id,
+                &pl.community_actor_id,
+                &pl.flair_name,
+                &pl.custom_text,
+                &RequestStatus::Pending.as_str(),
+                &Utc::now().to_rfc3339(),
+            ],
+        )
+        .await?;
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(result)
+}
+
+pub(crate) async fn get_flair_request(
+    client: &Client,
+    community_actor_id: &str,
+    user_actor_id: &str,
+) -> anyhow::Result<Option<FlairRequest>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by
+            FROM flair_requests
+            WHERE community_actor_id = $1 AND user_actor_id = $2
+        ",
+        )
+        .await?;
+
+    match client
+        .query_opt(&stmt, &[&community_actor_id, &user_actor_id])
+        .await?
+    {
+        Some(r) => Ok(Some(request_from_row(&r)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) async fn get_pending_requests(
+    client: &Client,
+    community_actor_id: &str,
+) -> anyhow::Result<Vec<FlairRequest>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by
+            FROM flair_requests
+            WHERE community_actor_id = $1 AND status = $2
+            ORDER BY requested_on
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[&community_actor_id, &RequestStatus::Pending.as_str()],
+        )
+        .await?
+        .iter()
+        .map(request_from_row)
+        .collect()
+}
+
+pub(crate) async fn decide_flair_request(
+    client: &mut Client,
+    pl: &DecideFlairRequestJson,
+    replaced: &[String],
+    decided_by: &str,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let status = if pl.approved {
+        RequestStatus::Approved
+    } else {
+        RequestStatus::Denied
+    };
+    let now = Utc::now().to_rfc3339();
+
+    let tx = client.transaction().await?;
+
+    let updated = tx
+        .execute(
+            r"UPDATE flair_requests SET status = $1, reason = $2, decided_on = $3, decided_by = $4
+            WHERE user_actor_id = $5 AND community_actor_id = $6 AND status = $7
+            ",
+            &[
+                &status.as_str(),
+                &pl.reason,
+                &now,
+                &decided_by,
+                &pl.user_actor_id,
+                &pl.community_actor_id,
+                &RequestStatus::Pending.as_str(),
+            ],
+        )
+        .await?;
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    if pl.approved {
+        let request = tx
+            .query_one(
+                r"SELECT flair_name, custom_text FROM flair_requests WHERE user_actor_id = $1 AND community_actor_id = $2",
+                &[&pl.user_actor_id, &pl.community_actor_id],
+            )
+            .await?;
+        let flair_name: String = request.try_get(0)?;
+        let custom_text: Option<String> = request.try_get(1)?;
+
+        place_user_flair(
+            &tx,
+            &pl.user_actor_id,
+            &pl.community_actor_id,
+            &flair_name,
+            &custom_text,
+            replaced,
+            decided_by,
+        )
+        .await?;
+    }
+
+    add_audit_entry(&tx, audit).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Appends an entry to the audit log, as part of the transaction of the change it describes
+async fn add_audit_entry(tx: &Transaction<'_>, entry: &NewAuditEntry) -> anyhow::Result<u64> {
+    let result = tx
+        .execute(
+            r"INSERT INTO flair_audit_log (actor_id, as_admin, action, community_actor_id, user_actor_id, flair_name, before_value, after_value, created_on)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ",
+            &[
+                &entry.actor_id,
+                &entry.as_admin,
+                &entry.action.as_str(),
+                &entry.community_actor_id,
+                &entry.user_actor_id,
+                &entry.flair_name,
+                &entry.before.as_ref().map(|v| v.to_string()),
+                &entry.after.as_ref().map(|v| v.to_string()),
+                &Utc::now().to_rfc3339(),
+            ],
+        )
+        .await?;
+
+    Ok(result)
+}
+
+fn audit_entry_from_row(r: &Row) -> anyhow::Result<AuditEntry> {
+    let action: String = r.try_get(3)?;
+    let created_on: String = r.try_get(9)?;
+
+    Ok(AuditEntry {
+        id: r.try_get(0)?,
+        actor_id: r.try_get(1)?,
+        as_admin: r.try_get(2)?,
+        action: action.parse().map_err(|e: String| anyhow!(e))?,
+        community_actor_id: r.try_get(4)?,
+        user_actor_id: r.try_get(5)?,
+        flair_name: r.try_get(6)?,
+        before: parse_audit_value(r.try_get(7)?)?,
+        after: parse_audit_value(r.try_get(8)?)?,
+        created_on: parse_timestamp(&created_on)?,
+    })
+}
+
+pub(crate) async fn get_audit_log(
+    client: &Client,
+    pl: &GetAuditLogJson,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT id, actor_id, as_admin, action, community_actor_id, user_actor_id, flair_name, before_value, after_value, created_on
+            FROM flair_audit_log
+            WHERE community_actor_id = $1
+                AND ($2::TEXT IS NULL OR action = $2)
+                AND ($3::TEXT IS NULL OR actor_id = $3)
+                AND ($4::TEXT IS NULL OR user_actor_id = $4)
+                AND ($5::TEXT IS NULL OR flair_name = $5)
+                AND ($6::BIGINT IS NULL OR id < $6)
+            ORDER BY id DESC
+            LIMIT $7
+        ",
+        )
+        .await?;
+
+    client
+        .query(
+            &stmt,
+            &[
+                &pl.community_actor_id,
+                &pl.action.map(|a| a.as_str()),
+                &pl.actor_id,
+                &pl.user_actor_id,
+                &pl.flair_name,
+                &pl.before,
+                &pl.limit.map(i64::from),
+            ],
+        )
+        .await?
+        .iter()
+        .map(audit_entry_from_row)
+        .collect()
+}
@@ -0,0 +1,1423 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_sqlite::rusqlite::params;
+use deadpool_sqlite::rusqlite::params_from_iter;
+use deadpool_sqlite::rusqlite::Connection;
+use deadpool_sqlite::rusqlite::Row;
+use deadpool_sqlite::{Pool, Runtime};
+
+use flair::{
+    AuditEntry, CommunitySettings, Flair, FlairGroup, FlairMember, FlairRequest, RequestStatus,
+    UserFlairEvent, UserFlairHistoryEntry,
+};
+
+use crate::db::migrations::SCHEMA_VERSION_TABLE;
+use crate::db::{parse_audit_value, parse_timestamp, FlairStore, Migration, NewAuditEntry};
+use crate::router::AddFlairGroupJson;
+use crate::router::AddFlairJson;
+use crate::router::AddUserFlairJson;
+use crate::router::DecideFlairRequestJson;
+use crate::router::DeleteFlairGroupJson;
+use crate::router::DeleteFlairJson;
+use crate::router::DeleteUserFlairJson;
+use crate::router::GetAuditLogJson;
+use crate::router::GetFlairsJson;
+use crate::router::GetUserFlairHistoryJson;
+use crate::router::GetUserFlairJson;
+use crate::router::ListUserFlairsJson;
+use crate::router::SetUserFlairsJson;
+
+/// SqliteStore keeps flairs in a SQLite database file
+pub(crate) struct SqliteStore {
+    pool: Pool,
+}
+
+impl SqliteStore {
+    pub(crate) fn connect(path: &str) -> anyhow::Result<Self> {
+        let pool = deadpool_sqlite::Config::new(path).create_pool(Runtime::Tokio1)?;
+
+        Ok(Self { pool })
+    }
+
+    /// Runs a closure on a pooled connection, rusqlite being a blocking library
+    async fn interact<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.pool.get().await?;
+
+        conn.interact(f)
+            .await
+            .map_err(|e| anyhow!("unable to interact with the database {}", e))?
+    }
+}
+
+#[async_trait]
+impl FlairStore for SqliteStore {
+    fn backend(&self) -> &'static str {
+        "SQLite"
+    }
+
+    async fn schema_version(&self) -> anyhow::Result<i64> {
+        self.interact(|conn| {
+            conn.execute_batch(SCHEMA_VERSION_TABLE)?;
+
+            Ok(conn.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                [],
+                |r| r.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> anyhow::Result<()> {
+        let (version, name, sql) = (migration.version, migration.name, migration.sqlite);
+
+        self.interact(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_on) VALUES (?, ?, ?)",
+                params![version, name, Utc::now().to_rfc3339()],
+            )?;
+
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    async fn get_user_flair(&self, pl: GetUserFlairJson) -> anyhow::Result<Option<Flair>> {
+        self.interact(move |conn| get_user_flair(conn, &pl)).await
+    }
+
+    async fn get_user_flair_list(&self, pl: GetUserFlairJson) -> anyhow::Result<Vec<Flair>> {
+        self.interact(move |conn| get_user_flair_list(conn, &pl))
+            .await
+    }
+
+    async fn list_user_flairs(&self, pl: ListUserFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        self.interact(move |conn| list_user_flairs(conn, &pl)).await
+    }
+
+    async fn get_user_flairs(
+        &self,
+        pairs: Vec<GetUserFlairJson>,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>> {
+        self.interact(move |conn| get_user_flairs(conn, &pairs))
+            .await
+    }
+
+    async fn get_flair(
+        &self,
+        community_actor_id: String,
+        name: String,
+    ) -> anyhow::Result<Option<Flair>> {
+        self.interact(move |conn| get_flair(conn, &community_actor_id, &name))
+            .await
+    }
+
+    async fn get_community_flairs(&self, pl: GetFlairsJson) -> anyhow::Result<Vec<Flair>> {
+        self.interact(move |conn| get_community_flairs(conn, &pl))
+            .await
+    }
+
+    async fn reorder_flairs(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        self.interact(move |conn| reorder_flairs(conn, &community_actor_id, &names, &audit))
+            .await
+    }
+
+    async fn get_flair_groups(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairGroup>> {
+        self.interact(move |conn| get_flair_groups(conn, &community_actor_id))
+            .await
+    }
+
+    async fn add_flair_group(
+        &self,
+        pl: AddFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| add_flair_group(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_flair_group(
+        &self,
+        pl: DeleteFlairGroupJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| delete_flair_group(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reorder_flair_groups(
+        &self,
+        community_actor_id: String,
+        names: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        self.interact(move |conn| reorder_flair_groups(conn, &community_actor_id, &names, &audit))
+            .await
+    }
+
+    async fn count_flair_users(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<HashMap<String, u32>> {
+        self.interact(move |conn| count_flair_users(conn, &community_actor_id))
+            .await
+    }
+
+    async fn get_flair_members(
+        &self,
+        community_actor_id: String,
+        flair_name: String,
+        before: Option<(DateTime<Utc>, String)>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<FlairMember>> {
+        self.interact(move |conn| {
+            get_flair_members(conn, &community_actor_id, &flair_name, &before, limit)
+        })
+        .await
+    }
+
+    async fn add_flair(&self, pl: AddFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        self.interact(move |conn| add_flair(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_flair(&self, pl: DeleteFlairJson, audit: NewAuditEntry) -> anyhow::Result<()> {
+        self.interact(move |conn| delete_flair(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn assign_user_flair(
+        &self,
+        pl: AddUserFlairJson,
+        replaced: Vec<String>,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| assign_user_flair(conn, &pl, &replaced, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_user_flair(
+        &self,
+        pl: DeleteUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| delete_user_flair(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_user_flairs(
+        &self,
+        pl: SetUserFlairsJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| set_user_flairs(conn, &pl, &audit))
+            .await
+    }
+
+    async fn count_flaired_users(&self, community_actor_id: String) -> anyhow::Result<u32> {
+        self.interact(move |conn| count_flaired_users(conn, &community_actor_id))
+            .await
+    }
+
+    async fn get_community_history(
+        &self,
+        community_actor_id: String,
+        limit: u32,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        self.interact(move |conn| get_community_history(conn, &community_actor_id, limit))
+            .await
+    }
+
+    async fn count_daily_assignments(
+        &self,
+        community_actor_id: String,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<HashMap<NaiveDate, u32>> {
+        self.interact(move |conn| count_daily_assignments(conn, &community_actor_id, from, to))
+            .await
+    }
+
+    async fn get_user_flair_history(
+        &self,
+        pl: GetUserFlairHistoryJson,
+    ) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+        self.interact(move |conn| get_user_flair_history(conn, &pl))
+            .await
+    }
+
+    async fn get_community_list(&self) -> anyhow::Result<Vec<String>> {
+        self.interact(get_community_list).await
+    }
+
+    async fn get_community_settings(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<CommunitySettings> {
+        self.interact(move |conn| get_community_settings(conn, community_actor_id))
+            .await
+    }
+
+    async fn set_community_settings(
+        &self,
+        settings: CommunitySettings,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| set_community_settings(conn, &settings, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_flair_request(
+        &self,
+        pl: AddUserFlairJson,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<()> {
+        self.interact(move |conn| create_flair_request(conn, &pl, &audit))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_flair_request(
+        &self,
+        community_actor_id: String,
+        user_actor_id: String,
+    ) -> anyhow::Result<Option<FlairRequest>> {
+        self.interact(move |conn| get_flair_request(conn, &community_actor_id, &user_actor_id))
+            .await
+    }
+
+    async fn get_pending_requests(
+        &self,
+        community_actor_id: String,
+    ) -> anyhow::Result<Vec<FlairRequest>> {
+        self.interact(move |conn| get_pending_requests(conn, &community_actor_id))
+            .await
+    }
+
+    async fn decide_flair_request(
+        &self,
+        pl: DecideFlairRequestJson,
+        replaced: Vec<String>,
+        decided_by: String,
+        audit: NewAuditEntry,
+    ) -> anyhow::Result<bool> {
+        self.interact(move |conn| decide_flair_request(conn, &pl, &replaced, &decided_by, &audit))
+            .await
+    }
+
+    async fn get_audit_log(&self, pl: GetAuditLogJson) -> anyhow::Result<Vec<AuditEntry>> {
+        self.interact(move |conn| get_audit_log(conn, &pl)).await
+    }
+}
+
+fn flair_from_row(r: &Row) -> deadpool_sqlite::rusqlite::Result<Flair> {
+    Ok(Flair::new(
+        r.get(0)?,
+        r.get(1)?,
+        r.get(2).unwrap_or_default(),
+        r.get(3)?,
+        r.get(4)?,
+        r.get(5)?,
+        r.get(6)?,
+        r.get(7)?,
+        r.get(8)?,
+        r.get(9)?,
+    ))
+}
+
+pub(crate) fn get_user_flair(
+    client: &mut Connection,
+    pl: &GetUserFlairJson,
+) -> anyhow::Result<Option<Flair>> {
+    Ok(get_user_flair_list(client, pl)?.into_iter().next())
+}
+
+pub(crate) fn get_user_flair_list(
+    client: &mut Connection,
+    pl: &GetUserFlairJson,
+) -> anyhow::Result<Vec<Flair>> {
+    let mut stmt = client.prepare_cached("
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE f.community_actor_id = ? AND uf.user_actor_id = ?
+            ORDER BY uf.position;
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![pl.community_actor_id, pl.user_actor_id])?;
+
+    let mut val: Vec<Flair> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(flair_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn list_user_flairs(
+    client: &mut Connection,
+    pl: &ListUserFlairsJson,
+) -> anyhow::Result<Vec<Flair>> {
+    let mut stmt = client.prepare_cached("
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE uf.user_actor_id = ?1 AND (?2 IS NULL OR uf.flair_community_actor_id > ?2)
+                AND uf.position = (
+                    SELECT MIN(position) FROM user_flairs first
+                    WHERE first.user_actor_id = uf.user_actor_id AND first.flair_community_actor_id = uf.flair_community_actor_id
+                )
+            ORDER BY uf.flair_community_actor_id
+            LIMIT ?3
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![pl.user_actor_id, pl.after, pl.limit])?;
+
+    let mut val: Vec<Flair> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(flair_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+/// Fetches the flairs of several (user, community) pairs in a single query.
+pub(crate) fn get_user_flairs(
+    client: &mut Connection,
+    pairs: &[GetUserFlairJson],
+) -> anyhow::Result<HashMap<String, HashMap<String, Vec<Flair>>>> {
+    let mut val: HashMap<String, HashMap<String, Vec<Flair>>> = HashMap::new();
+    if pairs.is_empty() {
+        return Ok(val);
+    }
+
+    let values = vec!["(?, ?)"; pairs.len()].join(", ");
+    let mut stmt = client.prepare(&format!(
+        "
+            SELECT f.name, COALESCE(uf.custom_text, f.display_name), f.path, f.community_actor_id, f.mod_only,
+                f.background_color, f.text_color, f.text_editable, f.max_length, f.group_name, uf.user_actor_id
+            FROM flairs f
+            JOIN user_flairs uf ON f.name = uf.flair_name AND f.community_actor_id = uf.flair_community_actor_id
+            WHERE (uf.user_actor_id, uf.flair_community_actor_id) IN (VALUES {values})
+            ORDER BY uf.position;
+        "
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(
+        pairs
+            .iter()
+            .flat_map(|p| [&p.user_actor_id, &p.community_actor_id]),
+    ))?;
+
+    while let Some(r) = rows.next()? {
+        let flair = flair_from_row(r)?;
+
+        val.entry(flair.community_actor_id.clone())
+            .or_default()
+            .entry(r.get(10)?)
+            .or_default()
+            .push(flair);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_community_flairs(
+    client: &mut Connection,
+    pl: &GetFlairsJson,
+) -> anyhow::Result<Vec<Flair>> {
+    //If mod only == true display both non mod and mod flairs
+    //If mod only == false display only non mod flairs
+    let mut stmt = client.prepare_cached(
+        "SELECT name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name
+            FROM flairs
+            WHERE community_actor_id = ? and mod_only <= ?
+            ORDER BY position, name
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![
+        pl.community_actor_id,
+        &pl.mod_only.unwrap_or(false)
+    ])?;
+
+    let mut val: Vec<Flair> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(flair_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_flair(
+    client: &mut Connection,
+    community_actor_id: &str,
+    name: &str,
+) -> anyhow::Result<Option<Flair>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name
+            FROM flairs
+            WHERE community_actor_id = ? AND name = ?
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id, name])?;
+
+    match rows.next()? {
+        Some(r) => Ok(Some(flair_from_row(r)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn reorder_flairs(
+    client: &mut Connection,
+    community_actor_id: &str,
+    names: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let tx = client.transaction()?;
+
+    let count: usize = tx.query_row(
+        "SELECT COUNT(*) FROM flairs WHERE community_actor_id = ?",
+        params![community_actor_id],
+        |r| r.get(0),
+    )?;
+    if count != names.len() {
+        return Ok(false);
+    }
+
+    for (position, name) in names.iter().enumerate() {
+        let updated = tx.execute(
+            r"UPDATE flairs SET position = ? WHERE community_actor_id = ? AND name = ?",
+            params![position, community_actor_id, name],
+        )?;
+        if updated == 0 {
+            return Ok(false);
+        }
+    }
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(true)
+}
+
+pub(crate) fn get_flair_groups(
+    client: &mut Connection,
+    community_actor_id: &str,
+) -> anyhow::Result<Vec<FlairGroup>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT name, display_name, community_actor_id, pick_one
+            FROM flair_groups
+            WHERE community_actor_id = ?
+            ORDER BY position, name
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id])?;
+
+    let mut val: Vec<FlairGroup> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(FlairGroup {
+            name: r.get(0)?,
+            display_name: r.get(1)?,
+            community_actor_id: r.get(2)?,
+            pick_one: r.get(3)?,
+        });
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn add_flair_group(
+    client: &mut Connection,
+    pl: &AddFlairGroupJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    let result = tx.execute(
+        r"INSERT INTO flair_groups (name, community_actor_id, display_name, pick_one, position)
+            VALUES (?1, ?2, ?3, ?4, (SELECT COALESCE(MAX(position) + 1, 0) FROM flair_groups WHERE community_actor_id = ?2))
+            ON CONFLICT (name, community_actor_id) DO UPDATE
+            SET display_name = excluded.display_name, pick_one = excluded.pick_one
+            ",
+        params![
+            pl.name,
+            pl.community_actor_id,
+            pl.display_name,
+            &pl.pick_one.unwrap_or(false),
+        ],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn delete_flair_group(
+    client: &mut Connection,
+    pl: &DeleteFlairGroupJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    tx.execute(
+        r"UPDATE flairs SET group_name = NULL WHERE group_name = ? AND community_actor_id = ?",
+        params![pl.name, pl.community_actor_id],
+    )?;
+
+    let result = tx.execute(
+        r"DELETE FROM flair_groups WHERE name = ? AND community_actor_id = ?",
+        params![pl.name, pl.community_actor_id],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn reorder_flair_groups(
+    client: &mut Connection,
+    community_actor_id: &str,
+    names: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let tx = client.transaction()?;
+
+    let count: usize = tx.query_row(
+        "SELECT COUNT(*) FROM flair_groups WHERE community_actor_id = ?",
+        params![community_actor_id],
+        |r| r.get(0),
+    )?;
+    if count != names.len() {
+        return Ok(false);
+    }
+
+    for (position, name) in names.iter().enumerate() {
+        let updated = tx.execute(
+            r"UPDATE flair_groups SET position = ? WHERE community_actor_id = ? AND name = ?",
+            params![position, community_actor_id, name],
+        )?;
+        if updated == 0 {
+            return Ok(false);
+        }
+    }
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(true)
+}
+
+pub(crate) fn count_flair_users(
+    client: &mut Connection,
+    community_actor_id: &str,
+) -> anyhow::Result<HashMap<String, u32>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT flair_name, COUNT(*)
+            FROM user_flairs
+            WHERE flair_community_actor_id = ?
+            GROUP BY flair_name
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id])?;
+
+    let mut val: HashMap<String, u32> = HashMap::new();
+    while let Some(r) = rows.next()? {
+        val.insert(r.get(0)?, r.get(1)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_flair_members(
+    client: &mut Connection,
+    community_actor_id: &str,
+    flair_name: &str,
+    before: &Option<(DateTime<Utc>, String)>,
+    limit: u32,
+) -> anyhow::Result<Vec<FlairMember>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT user_actor_id, custom_text, assigned_on
+            FROM user_flairs
+            WHERE flair_community_actor_id = ?1 AND flair_name = ?2
+                AND (?3 IS NULL OR (assigned_on, user_actor_id) < (?3, ?4))
+            ORDER BY assigned_on DESC, user_actor_id DESC
+            LIMIT ?5
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![
+        community_actor_id,
+        flair_name,
+        before.as_ref().map(|(date, _)| date.to_rfc3339()),
+        before.as_ref().map(|(_, user)| user),
+        limit,
+    ])?;
+
+    let mut val: Vec<FlairMember> = vec![];
+    while let Some(r) = rows.next()? {
+        let assigned_on: String = r.get(2)?;
+        val.push(FlairMember {
+            user_actor_id: r.get(0)?,
+            custom_text: r.get(1)?,
+            assigned_on: parse_timestamp(&assigned_on)?,
+        });
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn add_flair(
+    client: &mut Connection,
+    pl: &AddFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    let result = tx.execute(
+        r"INSERT INTO flairs (name, display_name, path, community_actor_id, mod_only, background_color, text_color, text_editable, max_length, group_name, position)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, (SELECT COALESCE(MAX(position) + 1, 0) FROM flairs WHERE community_actor_id = ?4))
+            ON CONFLICT (name, community_actor_id) DO UPDATE
+            SET display_name = excluded.display_name, path = excluded.path, mod_only = excluded.mod_only,
+                background_color = excluded.background_color, text_color = excluded.text_color,
+                text_editable = excluded.text_editable, max_length = excluded.max_length, group_name = excluded.group_name
+            ",
+        params![
+            pl.name,
+            pl.display_name,
+            pl.path,
+            pl.community_actor_id,
+            &pl.mod_only,
+            pl.background_color,
+            pl.text_color,
+            &pl.text_editable.unwrap_or(false),
+            pl.max_length,
+            pl.group_name,
+        ],
+    )?;
+
+    // Locking the text brings every user back to the flair's display name
+    if !pl.text_editable.unwrap_or(false) {
+        tx.execute(
+            r"UPDATE user_flairs SET custom_text = NULL WHERE flair_name = ? AND flair_community_actor_id = ?",
+            params![pl.name, pl.community_actor_id],
+        )?;
+    }
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn delete_flair(
+    client: &mut Connection,
+    pl: &DeleteFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    tx.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            SELECT user_actor_id, flair_community_actor_id, flair_name, custom_text, ?, ?, ?
+            FROM user_flairs
+            WHERE flair_name = ? AND flair_community_actor_id = ?
+            ",
+        params![
+            UserFlairEvent::Removed.as_str(),
+            audit.actor_id,
+            Utc::now().to_rfc3339(),
+            pl.name,
+            pl.community_actor_id,
+        ],
+    )?;
+
+    // Foreign keys aren't enforced by SQLite unless enabled, the flair's users are removed by hand
+    tx.execute(
+        r"DELETE FROM user_flairs WHERE flair_name = ? AND flair_community_actor_id = ?",
+        params![pl.name, pl.community_actor_id],
+    )?;
+
+    let result = tx.execute(
+        r"DELETE FROM flairs WHERE name = ? AND community_actor_id = ?",
+        params![pl.name, pl.community_actor_id],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn assign_user_flair(
+    client: &mut Connection,
+    pl: &AddUserFlairJson,
+    replaced: &[String],
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    let result = place_user_flair(
+        &tx,
+        &pl.user_actor_id,
+        &pl.community_actor_id,
+        &pl.flair_name,
+        &pl.custom_text,
+        replaced,
+        &audit.actor_id,
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+/// Saves a flair of a user in place of the replaced ones and records the assignment in their flair history
+fn place_user_flair(
+    client: &Connection,
+    user_actor_id: &str,
+    community_actor_id: &str,
+    flair_name: &str,
+    custom_text: &Option<String>,
+    replaced: &[String],
+    assigned_by: &str,
+) -> anyhow::Result<usize> {
+    let now = Utc::now().to_rfc3339();
+
+    // The flair keeps its place or takes the one of the first flair it replaces, otherwise it goes last
+    let mut position: Option<i64> = None;
+    let mut last: i64 = -1;
+    {
+        let mut stmt = client.prepare_cached(
+            "SELECT flair_name, position
+                FROM user_flairs
+                WHERE user_actor_id = ? AND flair_community_actor_id = ?
+                ORDER BY position
+            ",
+        )?;
+        let mut rows = stmt.query(params![user_actor_id, community_actor_id])?;
+        while let Some(r) = rows.next()? {
+            let name: String = r.get(0)?;
+            last = r.get(1)?;
+            if name == flair_name || replaced.contains(&name) {
+                position.get_or_insert(last);
+            }
+        }
+    }
+
+    {
+        let mut delete = client.prepare_cached(
+            r"DELETE FROM user_flairs WHERE user_actor_id = ? AND flair_community_actor_id = ? AND flair_name = ?",
+        )?;
+        for name in replaced.iter().map(String::as_str).chain([flair_name]) {
+            delete.execute(params![user_actor_id, community_actor_id, name])?;
+        }
+    }
+
+    let result = client.execute(
+        r"INSERT INTO user_flairs (user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text, position)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ",
+        params![
+            user_actor_id,
+            flair_name,
+            community_actor_id,
+            now,
+            custom_text,
+            position.unwrap_or(last + 1),
+        ],
+    )?;
+
+    client.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ",
+        params![
+            user_actor_id,
+            community_actor_id,
+            flair_name,
+            custom_text,
+            UserFlairEvent::Assigned.as_str(),
+            assigned_by,
+            now,
+        ],
+    )?;
+
+    Ok(result)
+}
+
+pub(crate) fn delete_user_flair(
+    client: &mut Connection,
+    pl: &DeleteUserFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    tx.execute(
+        r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+            SELECT user_actor_id, flair_community_actor_id, flair_name, custom_text, ?, ?, ?
+            FROM user_flairs
+            WHERE user_actor_id = ? AND flair_community_actor_id = ?
+            ",
+        params![
+            UserFlairEvent::Removed.as_str(),
+            audit.actor_id,
+            Utc::now().to_rfc3339(),
+            pl.user_actor_id,
+            pl.community_actor_id,
+        ],
+    )?;
+
+    let result = tx.execute(
+        r"DELETE FROM user_flairs WHERE user_actor_id = ? AND flair_community_actor_id = ?",
+        params![pl.user_actor_id, pl.community_actor_id],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn set_user_flairs(
+    client: &mut Connection,
+    pl: &SetUserFlairsJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<()> {
+    let now = Utc::now().to_rfc3339();
+
+    let tx = client.transaction()?;
+
+    // Flair name -> (assigned_on, custom_text) of the flairs the user holds
+    let mut current: BTreeMap<String, (String, Option<String>)> = BTreeMap::new();
+    {
+        let mut stmt = tx.prepare_cached(
+            "SELECT flair_name, assigned_on, custom_text
+                FROM user_flairs
+                WHERE user_actor_id = ? AND flair_community_actor_id = ?
+            ",
+        )?;
+        let mut rows = stmt.query(params![pl.user_actor_id, pl.community_actor_id])?;
+        while let Some(r) = rows.next()? {
+            current.insert(r.get(0)?, (r.get(1)?, r.get(2)?));
+        }
+    }
+
+    tx.execute(
+        r"DELETE FROM user_flairs WHERE user_actor_id = ? AND flair_community_actor_id = ?",
+        params![pl.user_actor_id, pl.community_actor_id],
+    )?;
+
+    {
+        let mut history = tx.prepare_cached(
+            r"INSERT INTO user_flair_history (user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                ",
+        )?;
+
+        for (flair_name, (_, custom_text)) in &current {
+            if !pl.flairs.iter().any(|f| &f.flair_name == flair_name) {
+                history.execute(params![
+                    pl.user_actor_id,
+                    pl.community_actor_id,
+                    flair_name,
+                    custom_text,
+                    UserFlairEvent::Removed.as_str(),
+                    audit.actor_id,
+                    now,
+                ])?;
+            }
+        }
+
+        for (position, choice) in pl.flairs.iter().enumerate() {
+            let kept = current
+                .get(&choice.flair_name)
+                .filter(|(_, custom_text)| custom_text == &choice.custom_text);
+
+            tx.execute(
+                r"INSERT INTO user_flairs (user_actor_id, flair_name, flair_community_actor_id, assigned_on, custom_text, position)
+                    VALUES (?, ?, ?, ?, ?, ?)
+                    ",
+                params![
+                    pl.user_actor_id,
+                    choice.flair_name,
+                    pl.community_actor_id,
+                    kept.map_or(&now, |(assigned_on, _)| assigned_on),
+                    choice.custom_text,
+                    position,
+                ],
+            )?;
+
+            if kept.is_none() {
+                history.execute(params![
+                    pl.user_actor_id,
+                    pl.community_actor_id,
+                    choice.flair_name,
+                    choice.custom_text,
+                    UserFlairEvent::Assigned.as_str(),
+                    audit.actor_id,
+                    now,
+                ])?;
+            }
+        }
+    }
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+pub(crate) fn count_flaired_users(
+    client: &mut Connection,
+    community_actor_id: &str,
+) -> anyhow::Result<u32> {
+    Ok(client.query_row(
+        "SELECT COUNT(DISTINCT user_actor_id) FROM user_flairs WHERE flair_community_actor_id = ?",
+        params![community_actor_id],
+        |r| r.get(0),
+    )?)
+}
+
+fn history_entry_from_row(r: &Row) -> anyhow::Result<UserFlairHistoryEntry> {
+    let event: String = r.get(5)?;
+    let changed_on: String = r.get(7)?;
+
+    Ok(UserFlairHistoryEntry {
+        id: r.get(0)?,
+        user_actor_id: r.get(1)?,
+        community_actor_id: r.get(2)?,
+        flair_name: r.get(3)?,
+        custom_text: r.get(4)?,
+        event: event.parse().map_err(|e: String| anyhow!(e))?,
+        changed_by: r.get(6)?,
+        changed_on: parse_timestamp(&changed_on)?,
+    })
+}
+
+pub(crate) fn get_community_history(
+    client: &mut Connection,
+    community_actor_id: &str,
+    limit: u32,
+) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT id, user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on
+            FROM user_flair_history
+            WHERE community_actor_id = ?
+            ORDER BY id DESC
+            LIMIT ?
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id, limit])?;
+
+    let mut val: Vec<UserFlairHistoryEntry> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(history_entry_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn count_daily_assignments(
+    client: &mut Connection,
+    community_actor_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> anyhow::Result<HashMap<NaiveDate, u32>> {
+    // Timestamps are stored in UTC, their first 10 characters being the date
+    let mut stmt = client.prepare_cached(
+        "SELECT substr(changed_on, 1, 10), COUNT(*)
+            FROM user_flair_history
+            WHERE community_actor_id = ? AND event = ? AND substr(changed_on, 1, 10) BETWEEN ? AND ?
+            GROUP BY substr(changed_on, 1, 10)
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![
+        community_actor_id,
+        UserFlairEvent::Assigned.as_str(),
+        from.to_string(),
+        to.to_string(),
+    ])?;
+
+    let mut val: HashMap<NaiveDate, u32> = HashMap::new();
+    while let Some(r) = rows.next()? {
+        let date: String = r.get(0)?;
+        val.insert(date.parse()?, r.get(1)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_user_flair_history(
+    client: &mut Connection,
+    pl: &GetUserFlairHistoryJson,
+) -> anyhow::Result<Vec<UserFlairHistoryEntry>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT id, user_actor_id, community_actor_id, flair_name, custom_text, event, changed_by, changed_on
+            FROM user_flair_history
+            WHERE community_actor_id = ?1 AND user_actor_id = ?2
+                AND (?3 IS NULL OR id < ?3)
+            ORDER BY id DESC
+            LIMIT ?4
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![
+        pl.community_actor_id,
+        pl.user_actor_id,
+        pl.before,
+        pl.limit,
+    ])?;
+
+    let mut val: Vec<UserFlairHistoryEntry> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(history_entry_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_community_list(client: &mut Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT f.community_actor_id
+            FROM flairs f
+            LEFT JOIN community_settings s ON f.community_actor_id = s.community_actor_id
+            WHERE COALESCE(s.flairs_enabled, 1)
+            GROUP BY f.community_actor_id
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![])?;
+
+    let mut val: Vec<String> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(r.get(0)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn get_community_settings(
+    client: &mut Connection,
+    community_actor_id: String,
+) -> anyhow::Result<CommunitySettings> {
+    let mut stmt = client.prepare_cached(
+        "SELECT flairs_enabled, self_assign, self_remove, require_approval, max_flairs
+            FROM community_settings
+            WHERE community_actor_id = ?
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id])?;
+
+    match rows.next()? {
+        Some(r) => Ok(CommunitySettings {
+            flairs_enabled: r.get(0)?,
+            self_assign: r.get(1)?,
+            self_remove: r.get(2)?,
+            require_approval: r.get(3)?,
+            max_flairs: r.get(4)?,
+            community_actor_id,
+        }),
+        None => Ok(CommunitySettings::new(community_actor_id)),
+    }
+}
+
+pub(crate) fn set_community_settings(
+    client: &mut Connection,
+    settings: &CommunitySettings,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    let result = tx.execute(
+        r"INSERT INTO community_settings (community_actor_id, flairs_enabled, self_assign, self_remove, require_approval, max_flairs)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (community_actor_id) DO UPDATE
+            SET flairs_enabled = excluded.flairs_enabled, self_assign = excluded.self_assign,
+                self_remove = excluded.self_remove, require_approval = excluded.require_approval,
+                max_flairs = excluded.max_flairs
+            ",
+        params![
+            settings.community_actor_id,
+            settings.flairs_enabled,
+            settings.self_assign,
+            settings.self_remove,
+            settings.require_approval,
+            settings.max_flairs,
+        ],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+fn request_from_row(r: &Row) -> anyhow::Result<FlairRequest> {
+    let status: String = r.get(4)?;
+    let requested_on: String = r.get(6)?;
+    let decided_on: Option<String> = r.get(7)?;
+
+    Ok(FlairRequest {
+        user_actor_id: r.get(0)?,
+        community_actor_id: r.get(1)?,
+        flair_name: r.get(2)?,
+        custom_text: r.get(3)?,
+        status: status.parse().map_err(|e: String| anyhow!(e))?,
+        reason: r.get(5)?,
+        requested_on: parse_timestamp(&requested_on)?,
+        decided_on: decided_on.as_deref().map(parse_timestamp).transpose()?,
+        decided_by: r.get(8)?,
+    })
+}
+
+pub(crate) fn create_flair_request(
+    client: &mut Connection,
+    pl: &AddUserFlairJson,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<usize> {
+    let tx = client.transaction()?;
+
+    let result = tx.execute(
+        r"INSERT INTO flair_requests (user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by)
+            VALUES (?, ?, ?, ?, ?, NULL, ?, NULL, NULL)
+            ON CONFLICT (user_actor_id, community_actor_id) DO UPDATE
+            SET flair_name = excluded.flair_name, custom_text = excluded.custom_text, status = excluded.status,
+                reason = NULL, requested_on = excluded.requested_on, decided_on = NULL, decided_by = NULL
+            ",
+        params![
+            pl.user_actor_id,
+            pl.community_actor_id,
+            pl.flair_name,
+            pl.custom_text,
+            RequestStatus::Pending.as_str(),
+            Utc::now().to_rfc3339(),
+        ],
+    )?;
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub(crate) fn get_flair_request(
+    client: &mut Connection,
+    community_actor_id: &str,
+    user_actor_id: &str,
+) -> anyhow::Result<Option<FlairRequest>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by
+            FROM flair_requests
+            WHERE community_actor_id = ? AND user_actor_id = ?
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id, user_actor_id])?;
+
+    match rows.next()? {
+        Some(r) => Ok(Some(request_from_row(r)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn get_pending_requests(
+    client: &mut Connection,
+    community_actor_id: &str,
+) -> anyhow::Result<Vec<FlairRequest>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT user_actor_id, community_actor_id, flair_name, custom_text, status, reason, requested_on, decided_on, decided_by
+            FROM flair_requests
+            WHERE community_actor_id = ? AND status = ?
+            ORDER BY requested_on
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![community_actor_id, RequestStatus::Pending.as_str()])?;
+
+    let mut val: Vec<FlairRequest> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(request_from_row(r)?);
+    }
+
+    Ok(val)
+}
+
+pub(crate) fn decide_flair_request(
+    client: &mut Connection,
+    pl: &DecideFlairRequestJson,
+    replaced: &[String],
+    decided_by: &str,
+    audit: &NewAuditEntry,
+) -> anyhow::Result<bool> {
+    let status = if pl.approved {
+        RequestStatus::Approved
+    } else {
+        RequestStatus::Denied
+    };
+    let now = Utc::now().to_rfc3339();
+
+    let tx = client.transaction()?;
+
+    let updated = tx.execute(
+        r"UPDATE flair_requests SET status = ?, reason = ?, decided_on = ?, decided_by = ?
+            WHERE user_actor_id = ? AND community_actor_id = ? AND status = ?
+            ",
+        params![
+            status.as_str(),
+            pl.reason,
+            now,
+            decided_by,
+            pl.user_actor_id,
+            pl.community_actor_id,
+            RequestStatus::Pending.as_str(),
+        ],
+    )?;
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    if pl.approved {
+        let (flair_name, custom_text): (String, Option<String>) = tx.query_row(
+            r"SELECT flair_name, custom_text FROM flair_requests WHERE user_actor_id = ? AND community_actor_id = ?",
+            params![pl.user_actor_id, pl.community_actor_id],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+
+        place_user_flair(
+            &tx,
+            &pl.user_actor_id,
+            &pl.community_actor_id,
+            &flair_name,
+            &custom_text,
+            replaced,
+            decided_by,
+        )?;
+    }
+
+    add_audit_entry(&tx, audit)?;
+    tx.commit()?;
+
+    Ok(true)
+}
+
+/// Appends an entry to the audit log, as part of the transaction of the change it describes
+fn add_audit_entry(client: &Connection, entry: &NewAuditEntry) -> anyhow::Result<usize> {
+    let result = client.execute(
+        r"INSERT INTO flair_audit_log (actor_id, as_admin, action, community_actor_id, user_actor_id, flair_name, before_value, after_value, created_on)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+        params![
+            entry.actor_id,
+            entry.as_admin,
+            entry.action.as_str(),
+            entry.community_actor_id,
+            entry.user_actor_id,
+            entry.flair_name,
+            entry.before.as_ref().map(|v| v.to_string()),
+            entry.after.as_ref().map(|v| v.to_string()),
+            Utc::now().to_rfc3339(),
+        ],
+    )?;
+
+    Ok(result)
+}
+
+fn audit_entry_from_row(r: &Row) -> anyhow::Result<AuditEntry> {
+    let action: String = r.get(3)?;
+    let created_on: String = r.get(9)?;
+
+    Ok(AuditEntry {
+        id: r.get(0)?,
+        actor_id: r.get(1)?,
+        as_admin: r.get(2)?,
+        action: action.parse().map_err(|e: String| anyhow!(e))?,
+        community_actor_id: r.get(4)?,
+        user_actor_id: r.get(5)?,
+        flair_name: r.get(6)?,
+        before: parse_audit_value(r.get(7)?)?,
+        after: parse_audit_value(r.get(8)?)?,
+        created_on: parse_timestamp(&created_on)?,
+    })
+}
+
+pub(crate) fn get_audit_log(
+    client: &mut Connection,
+    pl: &GetAuditLogJson,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT id, actor_id, as_admin, action, community_actor_id, user_actor_id, flair_name, before_value, after_value, created_on
+            FROM flair_audit_log
+            WHERE community_actor_id = ?1
+                AND (?2 IS NULL OR action = ?2)
+                AND (?3 IS NULL OR actor_id = ?3)
+                AND (?4 IS NULL OR user_actor_id = ?4)
+                AND (?5 IS NULL OR flair_name = ?5)
+                AND (?6 IS NULL OR id < ?6)
+            ORDER BY id DESC
+            LIMIT ?7
+        ",
+    )?;
+
+    let mut rows = stmt.query(params![
+        pl.community_actor_id,
+        pl.action.map(|a| a.as_str()),
+        pl.actor_id,
+        pl.user_actor_id,
+        pl.flair_name,
+        pl.before,
+        pl.limit,
+    ])?;
+
+    let mut val: Vec<AuditEntry> = vec![];
+    while let Some(r) = rows.next()? {
+        val.push(audit_entry_from_row(r)?);
+    }
+
+    Ok(val)
+}
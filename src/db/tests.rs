@@ -0,0 +1,1181 @@
+//! Storage scenarios shared by every backend, so that they all behave the same way.
+//! The Postgres ones only run with `cargo test -- --ignored`, against the database in `FLAIR_TEST_POSTGRES_URL`.
+
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flair::{AuditAction, CommunitySettings, RequestStatus, UserFlairEvent};
+
+use super::*;
+
+const USER: &str = "https://lemmy.test/u/user";
+const MOD: &str = "https://lemmy.test/u/mod";
+
+/// Runs every scenario in communities nobody used yet, the database doesn't need to be empty
+async fn run_scenarios(store: &dyn FlairStore) {
+    migrations::migrate(store).await.unwrap();
+    // Running the migrations again must be a no-op
+    assert!(migrations::migrate(store).await.unwrap().is_empty());
+    assert_eq!(
+        store.schema_version().await.unwrap(),
+        migrations::latest_version()
+    );
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let community = |name: &str| format!("https://lemmy.test/c/{name}{nanos}");
+
+    flairs(store, &community("flairs")).await;
+    assignments(store, &community("assignments")).await;
+    custom_text(store, &community("custom_text")).await;
+    settings(store, &community("settings")).await;
+    requests(store, &community("requests")).await;
+    audit_log(store, &community("audit_log")).await;
+    history(store, &community("history")).await;
+    listing(store, &community("listing")).await;
+    members(store, &community("members")).await;
+    stats(store, &community("stats")).await;
+    ordering(store, &community("ordering")).await;
+    groups(store, &community("groups")).await;
+    several_flairs(store, &community("several_flairs")).await;
+}
+
+fn new_flair(community: &str, name: &str, mod_only: bool) -> AddFlairJson {
+    AddFlairJson {
+        name: name.to_string(),
+        display_name: name.to_string(),
+        path: None,
+        community_actor_id: community.to_string(),
+        mod_only,
+        instance_domain: "lemmy.test".to_string(),
+        background_color: None,
+        text_color: None,
+        text_editable: None,
+        max_length: None,
+        group_name: None,
+    }
+}
+
+fn assignment(community: &str, flair_name: &str) -> AddUserFlairJson {
+    AddUserFlairJson {
+        user_actor_id: USER.to_string(),
+        community_actor_id: community.to_string(),
+        flair_name: flair_name.to_string(),
+        instance_domain: "lemmy.test".to_string(),
+        custom_text: None,
+    }
+}
+
+/// Entry recorded along with a change made by the mod
+fn audit(community: &str, action: AuditAction) -> NewAuditEntry {
+    NewAuditEntry {
+        actor_id: MOD.to_string(),
+        as_admin: false,
+        action,
+        community_actor_id: community.to_string(),
+        user_actor_id: None,
+        flair_name: None,
+        before: None,
+        after: None,
+    }
+}
+
+fn user_pair(community: &str) -> GetUserFlairJson {
+    GetUserFlairJson {
+        community_actor_id: community.to_string(),
+        user_actor_id: USER.to_string(),
+    }
+}
+
+/// Name of the user's flair, if they have one
+async fn user_flair(store: &dyn FlairStore, community: &str) -> Option<String> {
+    store
+        .get_user_flair(user_pair(community))
+        .await
+        .unwrap()
+        .map(|f| f.name)
+}
+
+/// Names and display names of the community's flairs, including the ones reserved to mods if asked
+async fn community_flairs(
+    store: &dyn FlairStore,
+    community: &str,
+    mod_only: bool,
+) -> Vec<(String, String)> {
+    let mut flairs: Vec<(String, String)> = store
+        .get_community_flairs(GetFlairsJson {
+            community_actor_id: community.to_string(),
+            mod_only: Some(mod_only),
+        })
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.name, f.display_name))
+        .collect();
+    flairs.sort();
+
+    flairs
+}
+
+fn names(flairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    flairs
+        .iter()
+        .map(|(name, display_name)| (name.to_string(), display_name.to_string()))
+        .collect()
+}
+
+async fn flairs(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    store
+        .add_flair(
+            new_flair(community, "based", true),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        community_flairs(store, community, true).await,
+        names(&[("auth", "auth"), ("based", "based")])
+    );
+    assert_eq!(
+        community_flairs(store, community, false).await,
+        names(&[("auth", "auth")])
+    );
+
+    // Adding a flair with the same name updates it
+    store
+        .add_flair(
+            AddFlairJson {
+                display_name: "Auth".to_string(),
+                background_color: Some("#ff4500".to_string()),
+                text_color: Some("#fff".to_string()),
+                ..new_flair(community, "auth", false)
+            },
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    let auth = store
+        .get_community_flairs(GetFlairsJson {
+            community_actor_id: community.to_string(),
+            mod_only: None,
+        })
+        .await
+        .unwrap()
+        .remove(0);
+    assert_eq!(auth.background_color.as_deref(), Some("#ff4500"));
+    assert_eq!(auth.text_color.as_deref(), Some("#fff"));
+    assert_eq!(
+        community_flairs(store, community, true).await,
+        names(&[("auth", "Auth"), ("based", "based")])
+    );
+    assert!(store
+        .get_community_list()
+        .await
+        .unwrap()
+        .contains(&community.to_string()));
+
+    // Deleting a flair takes it away from the users holding it
+    store
+        .assign_user_flair(
+            assignment(community, "based"),
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    store
+        .delete_flair(
+            DeleteFlairJson {
+                name: "based".to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::FlairDeleted),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        community_flairs(store, community, true).await,
+        names(&[("auth", "Auth")])
+    );
+    assert_eq!(user_flair(store, community).await, None);
+}
+
+async fn assignments(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    store
+        .add_flair(
+            new_flair(community, "based", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_flair(store, community).await, None);
+
+    store
+        .assign_user_flair(
+            assignment(community, "auth"),
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_flair(store, community).await.as_deref(), Some("auth"));
+
+    // Users hold a single flair per community
+    store
+        .assign_user_flair(
+            assignment(community, "based"),
+            vec!["auth".to_string()],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_flair(store, community).await.as_deref(), Some("based"));
+
+    let unflaired = GetUserFlairJson {
+        user_actor_id: "https://lemmy.test/u/nobody".to_string(),
+        ..user_pair(community)
+    };
+    let batch = store
+        .get_user_flairs(vec![user_pair(community), unflaired])
+        .await
+        .unwrap();
+    assert_eq!(batch[community].len(), 1);
+    assert_eq!(batch[community][USER][0].name, "based");
+
+    store
+        .delete_user_flair(
+            DeleteUserFlairJson {
+                user_actor_id: USER.to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::UserFlairRemoved),
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_flair(store, community).await, None);
+}
+
+async fn custom_text(store: &dyn FlairStore, community: &str) {
+    let editable = |text_editable: bool| AddFlairJson {
+        text_editable: Some(text_editable),
+        max_length: Some(10),
+        ..new_flair(community, "auth", false)
+    };
+    store
+        .add_flair(editable(true), audit(community, AuditAction::FlairCreated))
+        .await
+        .unwrap();
+    let flair = store
+        .get_flair(community.to_string(), "auth".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(flair.text_editable);
+    assert_eq!(flair.max_length, Some(10));
+
+    // The custom text replaces the display name
+    store
+        .assign_user_flair(
+            AddUserFlairJson {
+                custom_text: Some("Libertarian".to_string()),
+                ..assignment(community, "auth")
+            },
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    let flair = store.get_user_flair(user_pair(community)).await.unwrap();
+    assert_eq!(flair.unwrap().display_name, "Libertarian");
+
+    // Locking the text brings the user back to the display name
+    store
+        .add_flair(editable(false), audit(community, AuditAction::FlairCreated))
+        .await
+        .unwrap();
+    let flair = store.get_user_flair(user_pair(community)).await.unwrap();
+    assert_eq!(flair.unwrap().display_name, "auth");
+}
+
+async fn settings(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+
+    // Communities mods never configured use the defaults
+    let settings = store
+        .get_community_settings(community.to_string())
+        .await
+        .unwrap();
+    assert!(settings.flairs_enabled && settings.self_assign && settings.self_remove);
+    assert!(!settings.require_approval);
+
+    store
+        .set_community_settings(
+            CommunitySettings {
+                self_remove: false,
+                require_approval: true,
+                ..CommunitySettings::new(community.to_string())
+            },
+            audit(community, AuditAction::SettingsUpdated),
+        )
+        .await
+        .unwrap();
+    let settings = store
+        .get_community_settings(community.to_string())
+        .await
+        .unwrap();
+    assert!(!settings.self_remove);
+    assert!(settings.require_approval);
+
+    // Communities with flairs disabled aren't listed anymore
+    store
+        .set_community_settings(
+            CommunitySettings {
+                flairs_enabled: false,
+                ..settings
+            },
+            audit(community, AuditAction::SettingsUpdated),
+        )
+        .await
+        .unwrap();
+    assert!(!store
+        .get_community_list()
+        .await
+        .unwrap()
+        .contains(&community.to_string()));
+}
+
+fn decision(community: &str, approved: bool) -> DecideFlairRequestJson {
+    DecideFlairRequestJson {
+        community_actor_id: community.to_string(),
+        user_actor_id: USER.to_string(),
+        instance_domain: "lemmy.test".to_string(),
+        approved,
+        reason: Some("Not yet".to_string()),
+    }
+}
+
+async fn requests(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    store
+        .add_flair(
+            new_flair(community, "based", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+
+    // Nothing to decide without a request
+    assert!(!store
+        .decide_flair_request(
+            decision(community, true),
+            vec![],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestApproved)
+        )
+        .await
+        .unwrap());
+
+    // A new request replaces the previous one
+    store
+        .create_flair_request(
+            assignment(community, "auth"),
+            audit(community, AuditAction::FlairRequested),
+        )
+        .await
+        .unwrap();
+    store
+        .create_flair_request(
+            assignment(community, "based"),
+            audit(community, AuditAction::FlairRequested),
+        )
+        .await
+        .unwrap();
+    let pending = store
+        .get_pending_requests(community.to_string())
+        .await
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].flair_name, "based");
+    assert_eq!(pending[0].status, RequestStatus::Pending);
+    assert_eq!(user_flair(store, community).await, None);
+
+    // Denying keeps the request around with the reason, without assigning anything
+    assert!(store
+        .decide_flair_request(
+            decision(community, false),
+            vec![],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestDenied)
+        )
+        .await
+        .unwrap());
+    let request = store
+        .get_flair_request(community.to_string(), USER.to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.status, RequestStatus::Denied);
+    assert_eq!(request.reason.as_deref(), Some("Not yet"));
+    assert_eq!(request.decided_by.as_deref(), Some(MOD));
+    assert!(request.decided_on.is_some());
+    assert!(store
+        .get_pending_requests(community.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(user_flair(store, community).await, None);
+
+    // Decided requests can't be decided again
+    assert!(!store
+        .decide_flair_request(
+            decision(community, true),
+            vec![],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestApproved)
+        )
+        .await
+        .unwrap());
+
+    // Approving assigns the requested flair, with its custom text
+    store
+        .create_flair_request(
+            AddUserFlairJson {
+                custom_text: Some("Auth".to_string()),
+                ..assignment(community, "auth")
+            },
+            audit(community, AuditAction::FlairRequested),
+        )
+        .await
+        .unwrap();
+    assert!(store
+        .decide_flair_request(
+            decision(community, true),
+            vec![],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestApproved)
+        )
+        .await
+        .unwrap());
+    let flair = store.get_user_flair(user_pair(community)).await.unwrap();
+    assert_eq!(flair.unwrap().display_name, "Auth");
+    let request = store
+        .get_flair_request(community.to_string(), USER.to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.status, RequestStatus::Approved);
+}
+
+fn audit_log_query(community: &str) -> GetAuditLogJson {
+    GetAuditLogJson {
+        community_actor_id: community.to_string(),
+        instance_domain: "lemmy.test".to_string(),
+        action: None,
+        actor_id: None,
+        user_actor_id: None,
+        flair_name: None,
+        before: None,
+        limit: Some(50),
+    }
+}
+
+async fn audit_log(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            NewAuditEntry {
+                flair_name: Some("auth".to_string()),
+                after: Some(serde_json::json!({ "name": "auth" })),
+                ..audit(community, AuditAction::FlairCreated)
+            },
+        )
+        .await
+        .unwrap();
+    store
+        .assign_user_flair(
+            assignment(community, "auth"),
+            vec![],
+            NewAuditEntry {
+                user_actor_id: Some(USER.to_string()),
+                flair_name: Some("auth".to_string()),
+                ..audit(community, AuditAction::UserFlairAssigned)
+            },
+        )
+        .await
+        .unwrap();
+
+    // Nothing is recorded when there was nothing to decide
+    assert!(!store
+        .decide_flair_request(
+            decision(community, true),
+            vec![],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestApproved),
+        )
+        .await
+        .unwrap());
+
+    // Newest entries first
+    let log = store
+        .get_audit_log(audit_log_query(community))
+        .await
+        .unwrap();
+    let actions: Vec<AuditAction> = log.iter().map(|e| e.action).collect();
+    assert_eq!(
+        actions,
+        [AuditAction::UserFlairAssigned, AuditAction::FlairCreated]
+    );
+    assert_eq!(log[0].user_actor_id.as_deref(), Some(USER));
+    assert_eq!(log[1].actor_id, MOD);
+    assert_eq!(log[1].after, Some(serde_json::json!({ "name": "auth" })));
+
+    let created = store
+        .get_audit_log(GetAuditLogJson {
+            action: Some(AuditAction::FlairCreated),
+            ..audit_log_query(community)
+        })
+        .await
+        .unwrap();
+    assert_eq!(created.len(), 1);
+    let assigned = store
+        .get_audit_log(GetAuditLogJson {
+            user_actor_id: Some(USER.to_string()),
+            ..audit_log_query(community)
+        })
+        .await
+        .unwrap();
+    assert_eq!(assigned.len(), 1);
+
+    // Pages go back in time from the last entry received
+    let page = store
+        .get_audit_log(GetAuditLogJson {
+            before: Some(log[0].id),
+            limit: Some(1),
+            ..audit_log_query(community)
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].id, log[1].id);
+}
+
+fn history_query(community: &str) -> GetUserFlairHistoryJson {
+    GetUserFlairHistoryJson {
+        community_actor_id: community.to_string(),
+        user_actor_id: USER.to_string(),
+        before: None,
+        limit: Some(50),
+    }
+}
+
+async fn history(store: &dyn FlairStore, community: &str) {
+    for name in ["auth", "bird"] {
+        store
+            .add_flair(
+                new_flair(community, name, false),
+                audit(community, AuditAction::FlairCreated),
+            )
+            .await
+            .unwrap();
+    }
+    store
+        .assign_user_flair(
+            AddUserFlairJson {
+                custom_text: Some("Author".to_string()),
+                ..assignment(community, "auth")
+            },
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    store
+        .delete_user_flair(
+            DeleteUserFlairJson {
+                community_actor_id: community.to_string(),
+                user_actor_id: USER.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::UserFlairRemoved),
+        )
+        .await
+        .unwrap();
+    store
+        .assign_user_flair(
+            assignment(community, "bird"),
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    // Deleting the flair takes it away from its holders
+    store
+        .delete_flair(
+            DeleteFlairJson {
+                name: "bird".to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::FlairDeleted),
+        )
+        .await
+        .unwrap();
+
+    // Newest entries first, changes being attributed to the actor of the audit entry
+    let history = store
+        .get_user_flair_history(history_query(community))
+        .await
+        .unwrap();
+    let events: Vec<(&str, UserFlairEvent)> = history
+        .iter()
+        .map(|e| (e.flair_name.as_str(), e.event))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            ("bird", UserFlairEvent::Removed),
+            ("bird", UserFlairEvent::Assigned),
+            ("auth", UserFlairEvent::Removed),
+            ("auth", UserFlairEvent::Assigned),
+        ]
+    );
+    assert!(history.iter().all(|e| e.changed_by.as_deref() == Some(MOD)));
+    assert_eq!(history[2].custom_text.as_deref(), Some("Author"));
+
+    let page = store
+        .get_user_flair_history(GetUserFlairHistoryJson {
+            before: Some(history[1].id),
+            limit: Some(1),
+            ..history_query(community)
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].id, history[2].id);
+}
+
+/// Lists the flairs of a user in several communities, the user being named after the scenario
+/// so that the flairs of the other scenarios don't show up
+async fn listing(store: &dyn FlairStore, community: &str) {
+    let user = community.replace("/c/", "/u/");
+    let communities = [
+        format!("{community}-b"),
+        format!("{community}-a"),
+        format!("{community}-c"),
+    ];
+    for community in &communities {
+        store
+            .add_flair(
+                new_flair(community, "auth", false),
+                audit(community, AuditAction::FlairCreated),
+            )
+            .await
+            .unwrap();
+        store
+            .assign_user_flair(
+                AddUserFlairJson {
+                    user_actor_id: user.clone(),
+                    ..assignment(community, "auth")
+                },
+                vec![],
+                audit(community, AuditAction::UserFlairAssigned),
+            )
+            .await
+            .unwrap();
+    }
+
+    let query = |after: Option<&str>, limit| ListUserFlairsJson {
+        user_actor_id: user.clone(),
+        after: after.map(str::to_string),
+        limit: Some(limit),
+    };
+    let listed = |flairs: Vec<Flair>| -> Vec<String> {
+        flairs.into_iter().map(|f| f.community_actor_id).collect()
+    };
+
+    // Sorted by community
+    let flairs = store.list_user_flairs(query(None, 50)).await.unwrap();
+    let mut sorted = communities.to_vec();
+    sorted.sort();
+    assert_eq!(listed(flairs), sorted);
+
+    // Pages start after the community of the last flair received
+    let page = store.list_user_flairs(query(None, 2)).await.unwrap();
+    assert_eq!(listed(page), sorted[..2]);
+    let page = store
+        .list_user_flairs(query(Some(&sorted[1]), 2))
+        .await
+        .unwrap();
+    assert_eq!(listed(page), sorted[2..]);
+}
+
+async fn members(store: &dyn FlairStore, community: &str) {
+    for name in ["auth", "bird", "cat"] {
+        store
+            .add_flair(
+                new_flair(community, name, false),
+                audit(community, AuditAction::FlairCreated),
+            )
+            .await
+            .unwrap();
+    }
+    let users: Vec<String> = (1..=4)
+        .map(|i| format!("https://lemmy.test/u/member{i}"))
+        .collect();
+    for (user, flair_name) in users.iter().zip(["auth", "auth", "auth", "bird"]) {
+        store
+            .assign_user_flair(
+                AddUserFlairJson {
+                    user_actor_id: user.clone(),
+                    ..assignment(community, flair_name)
+                },
+                vec![],
+                audit(community, AuditAction::UserFlairAssigned),
+            )
+            .await
+            .unwrap();
+    }
+
+    // Flairs nobody holds are omitted
+    let counts = store
+        .count_flair_users(community.to_string())
+        .await
+        .unwrap();
+    assert_eq!(
+        counts,
+        HashMap::from([("auth".to_string(), 3), ("bird".to_string(), 1)])
+    );
+
+    // Most recently assigned first
+    let members = store
+        .get_flair_members(community.to_string(), "auth".to_string(), None, 50)
+        .await
+        .unwrap();
+    let holders: Vec<&str> = members.iter().map(|m| m.user_actor_id.as_str()).collect();
+    assert_eq!(holders, [&users[2], &users[1], &users[0]]);
+
+    // Pages go on from the last member received
+    let last = &members[0];
+    let page = store
+        .get_flair_members(
+            community.to_string(),
+            "auth".to_string(),
+            Some((last.assigned_on, last.user_actor_id.clone())),
+            1,
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].user_actor_id, users[1]);
+}
+
+async fn stats(store: &dyn FlairStore, community: &str) {
+    store
+        .add_flair(
+            new_flair(community, "auth", false),
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+    for user in [USER, MOD] {
+        store
+            .assign_user_flair(
+                AddUserFlairJson {
+                    user_actor_id: user.to_string(),
+                    ..assignment(community, "auth")
+                },
+                vec![],
+                audit(community, AuditAction::UserFlairAssigned),
+            )
+            .await
+            .unwrap();
+    }
+    store
+        .delete_user_flair(
+            DeleteUserFlairJson {
+                community_actor_id: community.to_string(),
+                user_actor_id: MOD.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::UserFlairRemoved),
+        )
+        .await
+        .unwrap();
+
+    // Newest first, whoever the users are
+    let history = store
+        .get_community_history(community.to_string(), 2)
+        .await
+        .unwrap();
+    let events: Vec<(&str, UserFlairEvent)> = history
+        .iter()
+        .map(|e| (e.user_actor_id.as_str(), e.event))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (MOD, UserFlairEvent::Removed),
+            (MOD, UserFlairEvent::Assigned)
+        ]
+    );
+
+    // Only assignments are counted, the window is wide enough not to depend on when the test runs
+    let today = Utc::now().date_naive();
+    let days = chrono::Days::new(1);
+    let daily = store
+        .count_daily_assignments(community.to_string(), today - days, today + days)
+        .await
+        .unwrap();
+    assert_eq!(daily.values().sum::<u32>(), 2);
+
+    let daily = store
+        .count_daily_assignments(
+            community.to_string(),
+            today - chrono::Days::new(7),
+            today - chrono::Days::new(2),
+        )
+        .await
+        .unwrap();
+    assert!(daily.is_empty());
+}
+
+/// Names of the community's flairs, in the order mods chose
+async fn flair_order(store: &dyn FlairStore, community: &str) -> Vec<String> {
+    store
+        .get_community_flairs(GetFlairsJson {
+            community_actor_id: community.to_string(),
+            mod_only: Some(true),
+        })
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|f| f.name)
+        .collect()
+}
+
+async fn ordering(store: &dyn FlairStore, community: &str) {
+    for name in ["cat", "auth", "bird"] {
+        store
+            .add_flair(
+                new_flair(community, name, name == "bird"),
+                audit(community, AuditAction::FlairCreated),
+            )
+            .await
+            .unwrap();
+    }
+    // New flairs are placed after the existing ones
+    assert_eq!(flair_order(store, community).await, ["cat", "auth", "bird"]);
+
+    let names = |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+    let reordered = audit(community, AuditAction::FlairsReordered);
+
+    // Every flair must be listed, unknown ones can't be
+    for invalid in [names(&["auth", "bird"]), names(&["auth", "bird", "dog"])] {
+        assert!(!store
+            .reorder_flairs(community.to_string(), invalid, reordered.clone())
+            .await
+            .unwrap());
+        assert_eq!(flair_order(store, community).await, ["cat", "auth", "bird"]);
+    }
+
+    assert!(store
+        .reorder_flairs(
+            community.to_string(),
+            names(&["bird", "auth", "cat"]),
+            reordered
+        )
+        .await
+        .unwrap());
+    assert_eq!(flair_order(store, community).await, ["bird", "auth", "cat"]);
+
+    // Only the reorder that happened is recorded
+    let log = store
+        .get_audit_log(GetAuditLogJson {
+            action: Some(AuditAction::FlairsReordered),
+            ..audit_log_query(community)
+        })
+        .await
+        .unwrap();
+    assert_eq!(log.len(), 1);
+}
+
+fn new_group(community: &str, name: &str, pick_one: bool) -> AddFlairGroupJson {
+    AddFlairGroupJson {
+        name: name.to_string(),
+        display_name: name.to_string(),
+        community_actor_id: community.to_string(),
+        instance_domain: "lemmy.test".to_string(),
+        pick_one: Some(pick_one),
+    }
+}
+
+/// Names of the community's flair groups, in the order mods chose
+async fn group_order(store: &dyn FlairStore, community: &str) -> Vec<String> {
+    store
+        .get_flair_groups(community.to_string())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|g| g.name)
+        .collect()
+}
+
+async fn groups(store: &dyn FlairStore, community: &str) {
+    for (name, pick_one) in [("country", false), ("team", true)] {
+        store
+            .add_flair_group(
+                new_group(community, name, pick_one),
+                audit(community, AuditAction::GroupCreated),
+            )
+            .await
+            .unwrap();
+    }
+    store
+        .add_flair(
+            AddFlairJson {
+                group_name: Some("country".to_string()),
+                ..new_flair(community, "fr", false)
+            },
+            audit(community, AuditAction::FlairCreated),
+        )
+        .await
+        .unwrap();
+
+    // Updating a group keeps its place
+    store
+        .add_flair_group(
+            AddFlairGroupJson {
+                display_name: "Country".to_string(),
+                ..new_group(community, "country", true)
+            },
+            audit(community, AuditAction::GroupUpdated),
+        )
+        .await
+        .unwrap();
+    let groups = store.get_flair_groups(community.to_string()).await.unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].display_name, "Country");
+    assert!(groups[0].pick_one);
+
+    let names = |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+    assert!(!store
+        .reorder_flair_groups(
+            community.to_string(),
+            names(&["team"]),
+            audit(community, AuditAction::GroupsReordered)
+        )
+        .await
+        .unwrap());
+    assert_eq!(group_order(store, community).await, ["country", "team"]);
+    assert!(store
+        .reorder_flair_groups(
+            community.to_string(),
+            names(&["team", "country"]),
+            audit(community, AuditAction::GroupsReordered)
+        )
+        .await
+        .unwrap());
+    assert_eq!(group_order(store, community).await, ["team", "country"]);
+
+    // The flairs of a deleted group are kept, outside of any group
+    store
+        .delete_flair_group(
+            DeleteFlairGroupJson {
+                name: "country".to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::GroupDeleted),
+        )
+        .await
+        .unwrap();
+    assert_eq!(group_order(store, community).await, ["team"]);
+    let flair = store
+        .get_flair(community.to_string(), "fr".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(flair.group_name, None);
+
+    // Every change is recorded, except the reorder that didn't happen
+    let log = store
+        .get_audit_log(audit_log_query(community))
+        .await
+        .unwrap();
+    assert_eq!(log.len(), 6);
+}
+
+/// Names of the user's flairs, in the order they're displayed
+async fn user_flair_list(store: &dyn FlairStore, community: &str) -> Vec<String> {
+    store
+        .get_user_flair_list(user_pair(community))
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|f| f.name)
+        .collect()
+}
+
+async fn several_flairs(store: &dyn FlairStore, community: &str) {
+    for name in ["auth", "based", "cat", "dog", "eel"] {
+        store
+            .add_flair(
+                new_flair(community, name, false),
+                audit(community, AuditAction::FlairCreated),
+            )
+            .await
+            .unwrap();
+    }
+
+    store
+        .set_user_flairs(
+            SetUserFlairsJson {
+                user_actor_id: USER.to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+                flairs: ["cat", "auth", "based"]
+                    .iter()
+                    .map(|name| crate::router::UserFlairChoice {
+                        flair_name: name.to_string(),
+                        custom_text: None,
+                    })
+                    .collect(),
+            },
+            audit(community, AuditAction::UserFlairsUpdated),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        user_flair_list(store, community).await,
+        ["cat", "auth", "based"]
+    );
+
+    // An assignment takes the place of the first flair it replaces, the other flairs are kept
+    store
+        .assign_user_flair(
+            assignment(community, "dog"),
+            vec!["auth".to_string()],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        user_flair_list(store, community).await,
+        ["cat", "dog", "based"]
+    );
+
+    // A flair the user holds keeps its place, a new one replacing nothing goes last
+    store
+        .assign_user_flair(
+            AddUserFlairJson {
+                custom_text: Some("Cat".to_string()),
+                ..assignment(community, "cat")
+            },
+            vec![],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    store
+        .assign_user_flair(
+            assignment(community, "auth"),
+            vec!["based".to_string(), "dog".to_string()],
+            audit(community, AuditAction::UserFlairAssigned),
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_flair_list(store, community).await, ["cat", "auth"]);
+    let flairs = store
+        .get_user_flair_list(user_pair(community))
+        .await
+        .unwrap();
+    assert_eq!(flairs[0].display_name, "Cat");
+
+    // Approvals replace flairs the same way
+    store
+        .create_flair_request(
+            assignment(community, "eel"),
+            audit(community, AuditAction::FlairRequested),
+        )
+        .await
+        .unwrap();
+    assert!(store
+        .decide_flair_request(
+            decision(community, true),
+            vec!["cat".to_string()],
+            MOD.to_string(),
+            audit(community, AuditAction::RequestApproved)
+        )
+        .await
+        .unwrap());
+    assert_eq!(user_flair_list(store, community).await, ["eel", "auth"]);
+
+    // Removing the user's flair removes all of them
+    store
+        .delete_user_flair(
+            DeleteUserFlairJson {
+                user_actor_id: USER.to_string(),
+                community_actor_id: community.to_string(),
+                instance_domain: "lemmy.test".to_string(),
+            },
+            audit(community, AuditAction::UserFlairRemoved),
+        )
+        .await
+        .unwrap();
+    assert!(user_flair_list(store, community).await.is_empty());
+}
+
+#[tokio::test]
+async fn memory_store() {
+    run_scenarios(connect(":memory:").unwrap().as_ref()).await;
+}
+
+#[tokio::test]
+async fn sqlite_store() {
+    let path = env::temp_dir().join(format!("flair-test-{}.db", std::process::id()));
+    let store = connect(path.to_str().unwrap()).unwrap();
+
+    run_scenarios(store.as_ref()).await;
+
+    drop(store);
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+#[ignore = "needs a Postgres database, set FLAIR_TEST_POSTGRES_URL"]
+async fn postgres_store() {
+    let url = env::var("FLAIR_TEST_POSTGRES_URL")
+        .expect("FLAIR_TEST_POSTGRES_URL must point to a Postgres database");
+
+    run_scenarios(connect(&url).unwrap().as_ref()).await;
+}
@@ -0,0 +1,117 @@
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection, TypedHeaderRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use ts_rs::TS;
+
+/// Machine readable reason of a failed request, frontends can branch on it.
+/// Codes are part of the API: existing ones must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub(crate) enum ErrorCode {
+    /// The JWT is missing, invalid or doesn't allow the operation
+    Unauthorized,
+    /// The community settings don't allow the operation
+    Forbidden,
+    /// The flair is reserved to the community mods
+    ModOnly,
+    NotFound,
+    /// The community or user doesn't exist on Lemmy, or was deleted
+    UnknownActor,
+    /// The request is malformed or contains invalid values
+    ValidationFailed,
+    /// The Lemmy instance couldn't be reached to verify the JWT
+    LemmyUnreachable,
+    /// The Lemmy instance isn't trusted to verify the JWT
+    UntrustedInstance,
+    DbError,
+}
+
+impl ErrorCode {
+    fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::ModOnly | ErrorCode::UntrustedInstance => {
+                StatusCode::FORBIDDEN
+            }
+            ErrorCode::NotFound | ErrorCode::UnknownActor => StatusCode::NOT_FOUND,
+            ErrorCode::ValidationFailed => StatusCode::BAD_REQUEST,
+            ErrorCode::LemmyUnreachable => StatusCode::BAD_GATEWAY,
+            ErrorCode::DbError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// ApiError is the body of every failed request: `{ "error": code, "message": ... }`
+#[derive(Debug, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct ApiError {
+    #[serde(skip)]
+    #[ts(skip)]
+    status: StatusCode,
+    pub error: ErrorCode,
+    /// Human readable explanation, not meant to be parsed
+    pub message: String,
+}
+
+impl ApiError {
+    pub(crate) fn new(error: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status: error.status(),
+            error,
+            message: message.into(),
+        }
+    }
+
+    /// Overrides the status code implied by the error code
+    pub(crate) fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(crate) fn unauthorized() -> Self {
+        Self::new(ErrorCode::Unauthorized, "Unauthorized")
+    }
+
+    pub(crate) fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub(crate) fn validation(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::ValidationFailed, message)
+    }
+
+    /// Logs a database error, which is then reported without details
+    pub(crate) fn db(err: impl std::fmt::Display) -> Self {
+        tracing::error!("{}", err);
+        Self::new(ErrorCode::DbError, "Database error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::validation(rejection.body_text()).with_status(rejection.status())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::validation(rejection.body_text()).with_status(rejection.status())
+    }
+}
+
+impl From<TypedHeaderRejection> for ApiError {
+    fn from(rejection: TypedHeaderRejection) -> Self {
+        Self::new(ErrorCode::Unauthorized, rejection.to_string())
+    }
+}
@@ -0,0 +1,28 @@
+use axum::{
+    extract::{FromRequest, FromRequestParts},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Same as axum's `Json`, rejecting malformed bodies with an `ApiError`
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub(crate) struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Same as axum's `Query`, rejecting malformed query strings with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub(crate) struct Query<T>(pub T);
+
+/// Same as axum's `TypedHeader`, rejecting missing headers (eg: the JWT) with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::TypedHeader), rejection(ApiError))]
+pub(crate) struct TypedHeader<T>(pub T);
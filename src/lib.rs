@@ -1,4 +1,4 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
@@ -15,15 +15,31 @@ pub struct Flair {
     /// Community where the flair exists
     pub community_actor_id: String,
     pub mod_only: bool,
+    /// Flair background color, as a hex string (eg: `#ff4500`)
+    pub background_color: Option<String>,
+    /// Flair text color, as a hex string (eg: `#ffffff`)
+    pub text_color: Option<String>,
+    /// Whether users can replace the displayed name with a text of their choice
+    pub text_editable: bool,
+    /// Maximum length of the users' custom text, if editable
+    pub max_length: Option<u32>,
+    /// Group the flair belongs to, if any
+    pub group_name: Option<String>,
 }
 
 impl Flair {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         display_name: String,
         path: Option<String>,
         community_actor_id: String,
         mod_only: bool,
+        background_color: Option<String>,
+        text_color: Option<String>,
+        text_editable: bool,
+        max_length: Option<u32>,
+        group_name: Option<String>,
     ) -> Self {
         Self {
             name,
@@ -31,10 +47,56 @@ impl Flair {
             path,
             community_actor_id,
             mod_only,
+            background_color,
+            text_color,
+            text_editable,
+            max_length,
+            group_name,
         }
     }
 }
 
+/// CommunitySettings represents how a community uses flairs, mods are never bound by the self-service rules
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct CommunitySettings {
+    pub community_actor_id: String,
+    /// Whether flairs can be assigned in the community at all
+    pub flairs_enabled: bool,
+    /// Whether users can pick a flair for themselves
+    pub self_assign: bool,
+    /// Whether users can remove their own flair
+    pub self_remove: bool,
+    /// Whether users' flair changes must be approved by a mod
+    pub require_approval: bool,
+    /// Maximum number of flairs a user can hold at once
+    pub max_flairs: u32,
+}
+
+impl CommunitySettings {
+    /// Settings of a community mods haven't configured yet
+    pub fn new(community_actor_id: String) -> Self {
+        Self {
+            community_actor_id,
+            flairs_enabled: true,
+            self_assign: true,
+            self_remove: true,
+            require_approval: false,
+            max_flairs: 1,
+        }
+    }
+}
+
+/// Checks whether a string is a hex color, either in the short (`#fff`) or in the long (`#ffffff`) form
+pub fn is_hex_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => {
+            (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
 /// FlairDirectory represents Lemmy instance users. It's designed to be flexable, and exist whether you use
 /// Flairs with your Lemmy's Postgres database or Sqlite, which is why the name's so ambiguous.
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -43,6 +105,8 @@ pub struct UserFlair {
     pub flair_name: String,
     pub flair_community_actor_id: String,
     pub assigned_on: DateTime<Utc>, // This represents a non-timezone-aware datetime
+    /// Text chosen by the user, replacing the flair's display name
+    pub custom_text: Option<String>,
 }
 
 impl UserFlair {
@@ -51,12 +115,298 @@ impl UserFlair {
         flair_name: String,
         flair_community_actor_id: String,
         assigned_on: DateTime<Utc>,
+        custom_text: Option<String>,
     ) -> Self {
         Self {
             user_actor_id,
             flair_name,
             flair_community_actor_id,
             assigned_on,
+            custom_text,
+        }
+    }
+}
+
+/// Status of a flair request, as decided by the community mods
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum RequestStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+impl RequestStatus {
+    /// Name of the status, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestStatus::Pending => "pending",
+            RequestStatus::Approved => "approved",
+            RequestStatus::Denied => "denied",
+        }
+    }
+}
+
+impl std::str::FromStr for RequestStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(RequestStatus::Pending),
+            "approved" => Ok(RequestStatus::Approved),
+            "denied" => Ok(RequestStatus::Denied),
+            _ => Err(format!("unknown request status '{s}'")),
         }
     }
 }
+
+/// FlairRequest represents a flair a user asked for in a community requiring mod approval.
+/// Users have at most one request per community, a new request replaces the previous one.
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairRequest {
+    pub user_actor_id: String,
+    pub community_actor_id: String,
+    pub flair_name: String,
+    /// Text replacing the flair's display name, if the user asked for one
+    pub custom_text: Option<String>,
+    pub status: RequestStatus,
+    /// Explanation given by the mod who decided on the request
+    pub reason: Option<String>,
+    pub requested_on: DateTime<Utc>,
+    pub decided_on: Option<DateTime<Utc>>,
+    /// Actor id of the mod who decided on the request
+    pub decided_by: Option<String>,
+}
+
+/// Change recorded in the audit log
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum AuditAction {
+    FlairCreated,
+    FlairUpdated,
+    FlairDeleted,
+    FlairsReordered,
+    GroupCreated,
+    GroupUpdated,
+    GroupDeleted,
+    GroupsReordered,
+    UserFlairAssigned,
+    UserFlairRemoved,
+    UserFlairsUpdated,
+    FlairRequested,
+    RequestApproved,
+    RequestDenied,
+    SettingsUpdated,
+}
+
+impl AuditAction {
+    /// Name of the action, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::FlairCreated => "flair_created",
+            AuditAction::FlairUpdated => "flair_updated",
+            AuditAction::FlairDeleted => "flair_deleted",
+            AuditAction::FlairsReordered => "flairs_reordered",
+            AuditAction::GroupCreated => "group_created",
+            AuditAction::GroupUpdated => "group_updated",
+            AuditAction::GroupDeleted => "group_deleted",
+            AuditAction::GroupsReordered => "groups_reordered",
+            AuditAction::UserFlairAssigned => "user_flair_assigned",
+            AuditAction::UserFlairRemoved => "user_flair_removed",
+            AuditAction::UserFlairsUpdated => "user_flairs_updated",
+            AuditAction::FlairRequested => "flair_requested",
+            AuditAction::RequestApproved => "request_approved",
+            AuditAction::RequestDenied => "request_denied",
+            AuditAction::SettingsUpdated => "settings_updated",
+        }
+    }
+}
+
+impl std::str::FromStr for AuditAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flair_created" => Ok(AuditAction::FlairCreated),
+            "flair_updated" => Ok(AuditAction::FlairUpdated),
+            "flair_deleted" => Ok(AuditAction::FlairDeleted),
+            "flairs_reordered" => Ok(AuditAction::FlairsReordered),
+            "group_created" => Ok(AuditAction::GroupCreated),
+            "group_updated" => Ok(AuditAction::GroupUpdated),
+            "group_deleted" => Ok(AuditAction::GroupDeleted),
+            "groups_reordered" => Ok(AuditAction::GroupsReordered),
+            "user_flair_assigned" => Ok(AuditAction::UserFlairAssigned),
+            "user_flair_removed" => Ok(AuditAction::UserFlairRemoved),
+            "user_flairs_updated" => Ok(AuditAction::UserFlairsUpdated),
+            "flair_requested" => Ok(AuditAction::FlairRequested),
+            "request_approved" => Ok(AuditAction::RequestApproved),
+            "request_denied" => Ok(AuditAction::RequestDenied),
+            "settings_updated" => Ok(AuditAction::SettingsUpdated),
+            _ => Err(format!("unknown audit action '{s}'")),
+        }
+    }
+}
+
+/// AuditEntry represents a change made to the flairs of a community, and who made it
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct AuditEntry {
+    /// Increasing identifier, used to page through the log
+    #[ts(type = "number")]
+    pub id: i64,
+    /// Actor id of the user who made the change, as verified through Lemmy
+    pub actor_id: String,
+    /// Whether the change was made by an instance admin acting as a mod
+    pub as_admin: bool,
+    pub action: AuditAction,
+    pub community_actor_id: String,
+    /// User whose flair or request was changed
+    pub user_actor_id: Option<String>,
+    pub flair_name: Option<String>,
+    /// Value before the change (eg: a flair, the settings of the community), if any
+    #[ts(type = "unknown")]
+    pub before: Option<serde_json::Value>,
+    /// Value after the change, if any
+    #[ts(type = "unknown")]
+    pub after: Option<serde_json::Value>,
+    pub created_on: DateTime<Utc>,
+}
+
+/// What happened to a user's flair
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum UserFlairEvent {
+    Assigned,
+    Removed,
+}
+
+impl UserFlairEvent {
+    /// Name of the event, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserFlairEvent::Assigned => "assigned",
+            UserFlairEvent::Removed => "removed",
+        }
+    }
+}
+
+impl std::str::FromStr for UserFlairEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "assigned" => Ok(UserFlairEvent::Assigned),
+            "removed" => Ok(UserFlairEvent::Removed),
+            _ => Err(format!("unknown user flair event '{s}'")),
+        }
+    }
+}
+
+/// UserFlairHistoryEntry represents a flair a user received or lost in a community
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct UserFlairHistoryEntry {
+    /// Increasing identifier, used to page through the history
+    #[ts(type = "number")]
+    pub id: i64,
+    pub user_actor_id: String,
+    pub community_actor_id: String,
+    pub flair_name: String,
+    /// Text the user had instead of the flair's display name, if any
+    pub custom_text: Option<String>,
+    pub event: UserFlairEvent,
+    /// Actor id of the user who made the change, unknown for flairs assigned before the history was recorded
+    pub changed_by: Option<String>,
+    pub changed_on: DateTime<Utc>,
+}
+
+/// FlairListing represents a flair of a community, along with how many users hold it
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairListing {
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub flair: Flair,
+    /// Number of users holding the flair in the community
+    pub user_count: u32,
+}
+
+/// FlairGroup represents a category of flairs in a community, such as "Country" or "Team"
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairGroup {
+    /// Identifier of the group, unique within the community
+    pub name: String,
+    /// Name of the group shown to the users
+    pub display_name: String,
+    pub community_actor_id: String,
+    /// Whether users may pick at most one flair of the group
+    pub pick_one: bool,
+}
+
+/// FlairGroupListing represents a group along with its flairs
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairGroupListing {
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub group: FlairGroup,
+    /// Flairs of the group, in the order chosen by the mods
+    pub flairs: Vec<FlairListing>,
+}
+
+/// GroupedFlairs represents the flairs of a community sorted into their groups
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct GroupedFlairs {
+    /// Groups of the community in the order chosen by the mods, empty ones included
+    pub groups: Vec<FlairGroupListing>,
+    /// Flairs belonging to no group
+    pub ungrouped: Vec<FlairListing>,
+}
+
+/// FlairMember represents a user holding a flair
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairMember {
+    pub user_actor_id: String,
+    /// Text the user chose instead of the flair's display name, if any
+    pub custom_text: Option<String>,
+    pub assigned_on: DateTime<Utc>,
+}
+
+/// FlairMembersPage represents a page of the users holding a flair, most recently assigned first
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct FlairMembersPage {
+    pub members: Vec<FlairMember>,
+    /// Opaque cursor to pass back to get the next page, missing on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// DailyAssignments represents how many flairs were assigned in a community on a day (UTC)
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct DailyAssignments {
+    pub date: NaiveDate,
+    pub assignments: u32,
+}
+
+/// CommunityStats represents how flairs are used in a community
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct CommunityStats {
+    pub community_actor_id: String,
+    /// Number of users holding a flair in the community
+    pub flaired_users: u32,
+    /// Every flair of the community, most held first
+    pub flairs: Vec<FlairListing>,
+    /// Flairs assigned on each day of the requested range, days without assignments included
+    pub assignments_per_day: Vec<DailyAssignments>,
+    /// Latest flairs users received or lost, newest first
+    pub recent_changes: Vec<UserFlairHistoryEntry>,
+}
@@ -1,36 +1,28 @@
-use axum::http::StatusCode;
 use axum::routing;
 use axum::Router;
 
 use clap::Parser;
-use deadpool_sqlite::Pool;
-use deadpool_sqlite::Runtime;
 use dotenv::dotenv;
-use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use crate::cli::{init_db, Commands};
+use crate::config::Config;
+use crate::db::FlairStore;
+use crate::verify::Verifier;
 
 mod cli;
+mod config;
 mod db;
+mod error;
+mod extract;
 mod router;
 mod verify;
 
 #[derive(Clone)]
 struct AppState {
-    pool: Pool,
-    lemmy_port: u16,
-    docker: bool,
-    lemmy_domain: String,
-}
-
-#[derive(Clone)]
-struct Env {
-    flairs_port: u16,
-    lemmy_port: u16,
-    docker: bool,
-    db_path: String,
-    lemmy_domain: String,
+    store: Arc<dyn FlairStore>,
+    verifier: Arc<Verifier>,
 }
 
 #[tokio::main]
@@ -41,19 +33,21 @@ async fn main() -> anyhow::Result<()> {
     let args = cli::Args::parse();
     match &args.command {
         Some(Commands::Serve) => {
-            let env = load_env();
-            println!("The flair server is now running with {} on port {}, polling the {} Lemmy instance on port {}!", if env.docker {"Docker"} else {"Cargo"}, env.flairs_port, env.lemmy_domain, env.lemmy_port);
+            let config = Config::load(args.config.as_deref())?;
 
             // Database setup
-            let db_config = deadpool_sqlite::Config::new(env.db_path);
-            let pool = db_config.create_pool(Runtime::Tokio1)?;
-            init_db(&pool).await?;
+            let store = db::connect(&config.database.url)?;
+            tracing::debug!("storing flairs with the {} backend", store.backend());
+            init_db(store.as_ref()).await?;
+
+            println!(
+                "The flair server is now running on port {}, polling the {} Lemmy instance at {}!",
+                config.server.port, config.lemmy.domain, config.lemmy.internal_url
+            );
 
             let app_state = AppState {
-                pool,
-                lemmy_port: env.lemmy_port,
-                docker: env.docker,
-                lemmy_domain: env.lemmy_domain,
+                store,
+                verifier: Arc::new(Verifier::new(&config)),
             };
 
             let app = Router::new()
@@ -61,6 +55,31 @@ async fn main() -> anyhow::Result<()> {
                 .route("/api/v1/user", routing::get(router::get_user_flair_api))
                 .route("/api/v1/user", routing::put(router::put_user_flair_api))
                 .route("/api/v1/user", routing::delete(router::delete_user_api))
+                .route(
+                    "/api/v1/user/request",
+                    routing::get(router::get_flair_request_api),
+                )
+                .route(
+                    "/api/v1/user/flairs",
+                    routing::get(router::list_user_flairs_api),
+                )
+                .route(
+                    "/api/v1/user/history",
+                    routing::get(router::get_user_flair_history_api),
+                )
+                .route("/api/v1/users", routing::post(router::get_user_flairs_api))
+                .route(
+                    "/api/v2/user",
+                    routing::get(router::get_user_flair_list_api),
+                )
+                .route(
+                    "/api/v2/user",
+                    routing::put(router::put_user_flair_list_api),
+                )
+                .route(
+                    "/api/v2/users",
+                    routing::post(router::get_user_flair_lists_api),
+                )
                 .route(
                     "/api/v1/community",
                     routing::get(router::get_community_flairs_api),
@@ -73,73 +92,86 @@ async fn main() -> anyhow::Result<()> {
                     "/api/v1/community",
                     routing::delete(router::delete_community_flairs_api),
                 )
+                .route(
+                    "/api/v1/community/order",
+                    routing::put(router::reorder_community_flairs_api),
+                )
+                .route(
+                    "/api/v1/community/grouped",
+                    routing::get(router::get_grouped_flairs_api),
+                )
+                .route(
+                    "/api/v1/community/groups",
+                    routing::get(router::get_flair_groups_api),
+                )
+                .route(
+                    "/api/v1/community/groups",
+                    routing::put(router::put_flair_group_api),
+                )
+                .route(
+                    "/api/v1/community/groups",
+                    routing::delete(router::delete_flair_group_api),
+                )
+                .route(
+                    "/api/v1/community/groups/order",
+                    routing::put(router::reorder_flair_groups_api),
+                )
+                .route(
+                    "/api/v1/community/settings",
+                    routing::get(router::get_community_settings_api),
+                )
+                .route(
+                    "/api/v1/community/settings",
+                    routing::put(router::put_community_settings_api),
+                )
+                .route(
+                    "/api/v1/community/requests",
+                    routing::get(router::get_flair_requests_api),
+                )
+                .route(
+                    "/api/v1/community/requests",
+                    routing::put(router::decide_flair_request_api),
+                )
+                .route(
+                    "/api/v1/community/stats",
+                    routing::get(router::get_community_stats_api),
+                )
+                .route(
+                    "/api/v1/community/members",
+                    routing::get(router::get_flair_members_api),
+                )
+                .route(
+                    "/api/v1/community/log",
+                    routing::get(router::get_audit_log_api),
+                )
                 .route(
                     "/api/v1/setup",
                     routing::get(router::get_community_list_api),
                 )
                 .with_state(app_state);
 
-            let addr = SocketAddr::from(([0, 0, 0, 0], env.flairs_port));
+            let addr = SocketAddr::from(([0, 0, 0, 0], config.server.port));
             tracing::debug!("listening on {}", addr);
             axum::Server::bind(&addr)
                 .serve(app.into_make_service())
-                .await
-                .unwrap();
+                .await?;
+        }
+        Some(Commands::Migrate) => {
+            let store = db::connect(&Config::load_database_url(args.config.as_deref())?)?;
+            init_db(store.as_ref()).await?;
+
+            println!(
+                "The {} database is up to date (schema version {})",
+                store.backend(),
+                db::migrations::latest_version()
+            );
+        }
+        Some(Commands::CheckConfig) => {
+            let config = Config::load(args.config.as_deref())?;
+            print!("{}", config.redacted());
         }
         None => {}
     }
 
     Ok(())
 }
-
-/// Loads the following environment variables:
-/// - `FLAIRS_PORT int`
-/// - `LEMMY_PORT int`
-/// - `DOCKER bool`
-/// - `FLAIR_DB_URL string`
-fn load_env() -> Env {
-    // Retrieve port where flair will run - defaults to 6969
-    let mut flairs_port_env = env::var("FLAIRS_PORT").unwrap_or(String::from("6969"));
-    if flairs_port_env.starts_with(":") {
-        eprintln!("Please remove the ':' on your FLAIRS_PORT environment variable");
-        flairs_port_env = flairs_port_env.trim_start_matches(":").to_string();
-    }
-    let flairs_port: u16 = flairs_port_env.parse().unwrap_or(6969);
-
-    // Retrieve port where Lemmy is running - defaults to 8536
-    let mut lemmy_port_env = env::var("LEMMY_PORT").unwrap_or(String::from("8536"));
-    if lemmy_port_env.starts_with(":") {
-        eprintln!("Please remove the ':' on your LEMMY_PORT environment variable");
-        lemmy_port_env = lemmy_port_env.trim_start_matches(":").to_string();
-    }
-    let lemmy_port: u16 = lemmy_port_env.parse().unwrap_or(8536);
-
-    // Check if Flair is running in a Docker container (true, default) or on bare metal with Cargo (false)
-    let docker_env = env::var("DOCKER").unwrap_or(String::from("true"));
-    let docker: bool = docker_env.parse().unwrap_or(false);
-
-    // Retrieve the path where the sqlite DB should be saved
-    let db_path = env::var("FLAIR_DB_URL").unwrap_or(String::from("./database/flairs.db"));
-
-    // Retrieve the domain of the current lemmy instance
-    let lemmy_domain =
-        env::var("LEMMY_DOMAIN").expect("The LEMMY_DOMAIN environment variable must be set");
-
-    return Env {
-        flairs_port,
-        lemmy_port,
-        docker,
-        db_path,
-        lemmy_domain,
-    };
-}
-
-/// Utility function for mapping any error into a `500 Internal Server Error`
-/// response.
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    eprintln!("{}", err.to_string());
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-}
@@ -1,23 +1,40 @@
 use axum::{
     debug_handler,
-    extract::{Json, Query, State, TypedHeader},
+    extract::State,
     headers::{authorization::Bearer, Authorization},
     http::StatusCode,
     response::Html,
 };
-use chrono::Utc;
-use deadpool_sqlite::rusqlite::params;
-use flair::Flair;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use flair::{
+    is_hex_color, AuditAction, AuditEntry, CommunitySettings, CommunityStats, DailyAssignments,
+    Flair, FlairGroup, FlairGroupListing, FlairListing, FlairMember, FlairMembersPage,
+    FlairRequest, GroupedFlairs, RequestStatus, UserFlairHistoryEntry,
+};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use ts_rs::TS;
 
 use crate::{
-    db::{add_flair, get_community_flairs, get_community_list, get_user_flair},
-    internal_error,
-    verify::{verify_mod, verify_user},
+    db::NewAuditEntry,
+    error::{ApiError, ErrorCode},
+    extract::{Json, Query, TypedHeader},
+    verify::{ActorKind, Role, Verified},
     AppState,
 };
 
+/// Maximum length of a user's custom text, used when the flair doesn't set one
+const DEFAULT_TEXT_MAX_LENGTH: u32 = 64;
+/// Upper bound for the maximum length mods can set on a flair
+const TEXT_MAX_LENGTH_LIMIT: u32 = 256;
+/// Maximum number of entries returned at once by paginated endpoints
+const PAGE_MAX_LIMIT: u32 = 100;
+/// Number of entries returned by paginated endpoints when the client doesn't ask for a limit
+const PAGE_DEFAULT_LIMIT: u32 = 50;
+/// Upper bound for the number of flairs mods can let users hold at once
+const MAX_FLAIRS_LIMIT: u32 = 10;
+
 #[derive(Debug, Deserialize, Serialize, Clone, TS)]
 #[ts(export)]
 pub(crate) struct AddUserFlairJson {
@@ -25,6 +42,10 @@ pub(crate) struct AddUserFlairJson {
     pub community_actor_id: String,
     pub flair_name: String,
     pub instance_domain: String,
+    /// Text replacing the flair's display name, only allowed if the flair is editable
+    #[serde(default)]
+    #[ts(optional)]
+    pub custom_text: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, TS)]
@@ -35,58 +56,260 @@ pub(crate) struct DeleteUserFlairJson {
     pub instance_domain: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, TS)]
+#[ts(export)]
+pub(crate) struct UserFlairChoice {
+    pub flair_name: String,
+    /// Text replacing the flair's display name, only allowed if the flair is editable
+    #[serde(default)]
+    #[ts(optional)]
+    pub custom_text: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, TS)]
+#[ts(export)]
+pub(crate) struct SetUserFlairsJson {
+    pub user_actor_id: String,
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    /// Every flair the user should hold, in the order they're displayed. An empty list removes them all
+    pub flairs: Vec<UserFlairChoice>,
+}
+
+/// Assigns a flair to a user in place of their self-chosen flairs, keeping the ones given by mods.
+/// Users can only pick flairs that exist in the community and aren't reserved to mods
 #[debug_handler]
 pub(crate) async fn put_user_flair_api(
     State(state): State<AppState>,
     TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
-    Json(payload): Json<AddUserFlairJson>,
-) -> (StatusCode, String) {
-    match verify_user(
-        &state.lemmy_port,
-        &state.docker,
-        &jwt.token(),
-        &payload.user_actor_id,
-        &payload.community_actor_id,
-        &state.lemmy_domain,
-        &payload.instance_domain,
-    )
-    .await
-    {
-        Ok(true) => (),
-        Ok(false) | Err(_) => return (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+    Json(mut payload): Json<AddUserFlairJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_user(
+            jwt.token(),
+            &payload.user_actor_id,
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+    let role = verified.role;
+
+    let settings = state
+        .store
+        .get_community_settings(payload.community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    if !settings.flairs_enabled {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Flairs are disabled in this community",
+        ));
     }
 
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return internal_error(e),
-    };
+    if role == Role::Owner && !settings.self_assign {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Only mods can assign flairs in this community",
+        ));
+    }
+
+    let flair = state
+        .store
+        .get_flair(
+            payload.community_actor_id.clone(),
+            payload.flair_name.clone(),
+        )
+        .await
+        .map_err(ApiError::db)?
+        .ok_or_else(|| ApiError::not_found("Flair not found"))?;
+
+    if flair.mod_only && !role.is_mod() {
+        return Err(ApiError::new(
+            ErrorCode::ModOnly,
+            format!("Flair '{}' can only be assigned by mods", flair.name),
+        ));
+    }
+
+    // Owners were verified through their JWT, mods may assign flairs to any actor id
+    if role.is_mod() {
+        state
+            .verifier
+            .check_actor(
+                ActorKind::Person,
+                &payload.user_actor_id,
+                jwt.token(),
+                &payload.instance_domain,
+            )
+            .await?;
+    }
+
+    // An empty custom text means going back to the flair's display name
+    payload.custom_text = payload
+        .custom_text
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty());
+
+    if let Some(text) = &payload.custom_text {
+        validate_custom_text(text, &flair).map_err(ApiError::validation)?;
+    }
 
     let flair_name = payload.flair_name.clone();
     let user_name = payload.user_actor_id.clone();
 
-    if let Err(e) = conn
-        .interact(move |conn| {
-            return conn.execute(
-                r"INSERT OR REPLACE INTO user_flairs (user_actor_id, flair_name, flair_community_actor_id, assigned_on)
-                VALUES (?, ?, ?, ?)
-                ",
-                params![
-                    payload.user_actor_id,
-                    payload.flair_name,
-                    payload.community_actor_id,
-                    Utc::now().to_rfc3339(),
-                ],
-            );
-        })
-        .await
-    {
-        return crate::internal_error(e);
+    // Checked before queueing requests too, so that users know right away if they can't get the flair
+    let replaced = replaced_flairs(&state, &user_name, &flair, &settings, role).await?;
+
+    // The flair as it will be displayed, custom text included
+    let mut displayed = flair.clone();
+    if let Some(text) = &payload.custom_text {
+        displayed.display_name = text.clone();
+    }
+    let mut entry = NewAuditEntry {
+        user_actor_id: Some(user_name.clone()),
+        flair_name: Some(flair_name.clone()),
+        after: to_audit_value(&displayed),
+        ..audit_entry(
+            &verified,
+            AuditAction::UserFlairAssigned,
+            &payload.community_actor_id,
+        )
+    };
+
+    // Users' changes go through the approval queue, mods' changes are applied right away
+    if role == Role::Owner && settings.require_approval {
+        entry.action = AuditAction::FlairRequested;
+        state
+            .store
+            .create_flair_request(payload, entry)
+            .await
+            .map_err(ApiError::db)?;
+
+        return Ok((
+            StatusCode::ACCEPTED,
+            format!("Requested flair '{flair_name}', waiting for a mod's approval"),
+        ));
     }
 
-    (
+    let before = current_user_flairs(&state, &payload.community_actor_id, &user_name).await?;
+    entry.before = to_audit_value(&before);
+
+    state
+        .store
+        .assign_user_flair(payload, replaced, entry)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok((
         StatusCode::CREATED,
         format!("Assigned flair '{flair_name}' to user '{user_name}'"),
-    )
+    ))
+}
+
+/// Checks that a user's custom text is allowed by the flair, fits its maximum length
+/// and doesn't contain control characters (eg: newlines)
+fn validate_custom_text(text: &str, flair: &Flair) -> Result<(), String> {
+    if !flair.text_editable {
+        return Err(format!(
+            "The text of flair '{}' can't be edited",
+            flair.name
+        ));
+    }
+
+    let max_length = flair.max_length.unwrap_or(DEFAULT_TEXT_MAX_LENGTH);
+    if text.chars().count() > max_length as usize {
+        return Err(format!(
+            "Custom text is too long, the maximum is {max_length} characters"
+        ));
+    }
+
+    if text.chars().any(char::is_control) {
+        return Err("Custom text can't contain control characters".to_string());
+    }
+
+    Ok(())
+}
+
+/// Works out which flairs a v1 assignment replaces: the flair takes the place of the user's self-chosen flairs,
+/// or goes next to them if it's reserved to mods, while the mod only flairs are kept.
+/// Flairs that don't fit next to the new one (`max_flairs`, pick one groups) are replaced too,
+/// but users can't drop a mod only flair this way, nor remove flairs when self-removal is disabled
+async fn replaced_flairs(
+    state: &AppState,
+    user_actor_id: &str,
+    flair: &Flair,
+    settings: &CommunitySettings,
+    role: Role,
+) -> Result<Vec<String>, ApiError> {
+    let held = current_user_flairs(state, &flair.community_actor_id, user_actor_id).await?;
+    let groups = state
+        .store
+        .get_flair_groups(flair.community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+    let pick_one = flair
+        .group_name
+        .as_ref()
+        .filter(|name| groups.iter().any(|g| &&g.name == name && g.pick_one));
+
+    let mut replaced: Vec<&Flair> = vec![];
+    let mut kept: Vec<&Flair> = vec![];
+    for held_flair in held.iter().filter(|f| f.name != flair.name) {
+        let self_chosen = !flair.mod_only && !held_flair.mod_only;
+        let same_group = pick_one.is_some() && held_flair.group_name.as_ref() == pick_one;
+        if self_chosen || same_group {
+            replaced.push(held_flair);
+        } else {
+            kept.push(held_flair);
+        }
+    }
+
+    // The last flairs make room for the new one
+    let excess = (kept.len() + 1).saturating_sub(settings.max_flairs as usize);
+    replaced.extend(kept.split_off(kept.len().saturating_sub(excess)));
+
+    if role == Role::Owner {
+        keep_mod_flairs(replaced.iter().copied())?;
+
+        // Swapping a flair for the new one is fine, dropping more of them is a removal
+        let swapped = usize::from(!held.iter().any(|f| f.name == flair.name));
+        if !settings.self_remove && replaced.len() > swapped {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Only mods can remove flairs in this community",
+            ));
+        }
+    }
+
+    Ok(replaced.into_iter().map(|f| f.name.clone()).collect())
+}
+
+/// Stops users from getting rid of the flairs reserved to mods that they were given
+fn keep_mod_flairs<'a>(removed: impl IntoIterator<Item = &'a Flair>) -> Result<(), ApiError> {
+    match removed.into_iter().find(|f| f.mod_only) {
+        Some(flair) => Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "Flair '{}' was given by mods, only mods can remove it",
+                flair.name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks the page size asked by the client, falling back to the default one
+fn page_limit(limit: Option<u32>) -> Result<u32, ApiError> {
+    let limit = limit.unwrap_or(PAGE_DEFAULT_LIMIT);
+    if limit == 0 || limit > PAGE_MAX_LIMIT {
+        return Err(ApiError::validation(format!(
+            "Limit must be between 1 and {PAGE_MAX_LIMIT}"
+        )));
+    }
+
+    Ok(limit)
 }
 
 #[debug_handler]
@@ -94,45 +317,260 @@ pub(crate) async fn delete_user_api(
     State(state): State<AppState>,
     TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
     Json(payload): Json<DeleteUserFlairJson>,
-) -> (StatusCode, String) {
-    match verify_user(
-        &state.lemmy_port,
-        &state.docker,
-        &jwt.token(),
-        &payload.user_actor_id,
-        &payload.community_actor_id,
-        &state.lemmy_domain,
-        &payload.instance_domain,
-    )
-    .await
-    {
-        Ok(true) => (),
-        Ok(false) | Err(_) => return (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_user(
+            jwt.token(),
+            &payload.user_actor_id,
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let actor_id = payload.user_actor_id.clone();
+    let before = current_user_flairs(&state, &payload.community_actor_id, &actor_id).await?;
+
+    // Removing a flair is allowed even if flairs are disabled, so that users can clean up after them
+    if verified.role == Role::Owner {
+        let settings = state
+            .store
+            .get_community_settings(payload.community_actor_id.clone())
+            .await
+            .map_err(ApiError::db)?;
+
+        if !settings.self_remove {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Only mods can remove flairs in this community",
+            ));
+        }
+
+        keep_mod_flairs(&before)?;
     }
 
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return internal_error(e),
+    // Every flair of the user is removed, the flair name is only recorded when there's a single one
+    let entry = NewAuditEntry {
+        user_actor_id: Some(actor_id.clone()),
+        flair_name: match before.as_slice() {
+            [flair] => Some(flair.name.clone()),
+            _ => None,
+        },
+        before: to_audit_value(&before),
+        ..audit_entry(
+            &verified,
+            AuditAction::UserFlairRemoved,
+            &payload.community_actor_id,
+        )
     };
 
-    let actor_id = payload.user_actor_id.clone();
+    state
+        .store
+        .delete_user_flair(payload, entry)
+        .await
+        .map_err(ApiError::db)?;
 
-    if let Err(e) = conn
-        .interact(move |conn| {
-            return conn.execute(
-                r"DELETE FROM user_flairs WHERE user_actor_id = ? AND flair_community_actor_id = ?",
-                params![payload.user_actor_id, payload.community_actor_id],
-            );
-        })
+    Ok((
+        StatusCode::OK,
+        format!("Removed flair from user '{actor_id}'"),
+    ))
+}
+
+/// Lists the flairs of a user in a community, in the order they're displayed
+#[debug_handler]
+pub(crate) async fn get_user_flair_list_api(
+    State(state): State<AppState>,
+    Query(payload): Query<GetUserFlairJson>,
+) -> Result<Json<Vec<Flair>>, ApiError> {
+    let flairs = state
+        .store
+        .get_user_flair_list(payload)
         .await
-    {
-        return crate::internal_error(e);
+        .map_err(ApiError::db)?;
+
+    Ok(Json(flairs))
+}
+
+/// Replaces every flair of a user in a community. Each flair goes through the same checks as
+/// the single flair endpoint, the list must fit the community's `max_flairs`
+/// and hold at most one flair of each `pick_one` group.
+/// Flairs the user already holds, with the same text, don't count as new assignments.
+#[debug_handler]
+pub(crate) async fn put_user_flair_list_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(mut payload): Json<SetUserFlairsJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_user(
+            jwt.token(),
+            &payload.user_actor_id,
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+    let role = verified.role;
+
+    let unique: HashSet<&String> = payload.flairs.iter().map(|f| &f.flair_name).collect();
+    if unique.len() != payload.flairs.len() {
+        return Err(ApiError::validation("Every flair must be listed only once"));
+    }
+
+    let settings = state
+        .store
+        .get_community_settings(payload.community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    if payload.flairs.len() > settings.max_flairs as usize {
+        return Err(ApiError::validation(format!(
+            "Too many flairs, users can hold at most {} in this community",
+            settings.max_flairs
+        )));
+    }
+
+    let user_name = payload.user_actor_id.clone();
+    let community_actor_id = payload.community_actor_id.clone();
+    let before = current_user_flairs(&state, &community_actor_id, &user_name).await?;
+
+    // The flairs as they will be displayed, custom text included
+    let mut after: Vec<Flair> = vec![];
+    let mut assigns = false;
+    for choice in payload.flairs.iter_mut() {
+        let flair = state
+            .store
+            .get_flair(community_actor_id.clone(), choice.flair_name.clone())
+            .await
+            .map_err(ApiError::db)?
+            .ok_or_else(|| ApiError::not_found("Flair not found"))?;
+
+        // An empty custom text means going back to the flair's display name
+        choice.custom_text = choice
+            .custom_text
+            .take()
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty());
+
+        let mut displayed = flair.clone();
+        if let Some(text) = &choice.custom_text {
+            displayed.display_name = text.clone();
+        }
+
+        let unchanged = before
+            .iter()
+            .any(|f| f.name == displayed.name && f.display_name == displayed.display_name);
+        if !unchanged {
+            assigns = true;
+
+            if flair.mod_only && !role.is_mod() {
+                return Err(ApiError::new(
+                    ErrorCode::ModOnly,
+                    format!("Flair '{}' can only be assigned by mods", flair.name),
+                ));
+            }
+
+            if let Some(text) = &choice.custom_text {
+                validate_custom_text(text, &flair).map_err(ApiError::validation)?;
+            }
+        }
+
+        after.push(displayed);
+    }
+
+    let groups = state
+        .store
+        .get_flair_groups(community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+    for group in groups.iter().filter(|g| g.pick_one) {
+        let picked = after
+            .iter()
+            .filter(|f| f.group_name.as_ref() == Some(&group.name))
+            .count();
+        if picked > 1 {
+            return Err(ApiError::validation(format!(
+                "Only one flair of group '{}' can be picked",
+                group.display_name
+            )));
+        }
+    }
+
+    if assigns {
+        if !settings.flairs_enabled {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Flairs are disabled in this community",
+            ));
+        }
+
+        if role == Role::Owner && !settings.self_assign {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Only mods can assign flairs in this community",
+            ));
+        }
+
+        // Requests hold a single flair, users go through the v1 endpoint to ask for one
+        if role == Role::Owner && settings.require_approval {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Flairs must be approved by a mod in this community, request them through /api/v1/user",
+            ));
+        }
+    }
+
+    let removed: Vec<&Flair> = before
+        .iter()
+        .filter(|f| !payload.flairs.iter().any(|c| c.flair_name == f.name))
+        .collect();
+    if !removed.is_empty() && role == Role::Owner {
+        if !settings.self_remove {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Only mods can remove flairs in this community",
+            ));
+        }
+
+        keep_mod_flairs(removed)?;
+    }
+
+    // Owners were verified through their JWT, mods may assign flairs to any actor id
+    if assigns && role.is_mod() {
+        state
+            .verifier
+            .check_actor(
+                ActorKind::Person,
+                &user_name,
+                jwt.token(),
+                &payload.instance_domain,
+            )
+            .await?;
     }
 
-    (
+    let entry = NewAuditEntry {
+        user_actor_id: Some(user_name.clone()),
+        before: to_audit_value(&before),
+        after: to_audit_value(&after),
+        ..audit_entry(
+            &verified,
+            AuditAction::UserFlairsUpdated,
+            &community_actor_id,
+        )
+    };
+
+    state
+        .store
+        .set_user_flairs(payload, entry)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok((
         StatusCode::OK,
-        format!("Removed flair from user '{actor_id}'"),
-    )
+        format!("Updated the flairs of user '{user_name}'"),
+    ))
 }
 
 #[debug_handler]
@@ -151,43 +589,121 @@ pub(crate) struct AddFlairJson {
     pub community_actor_id: String,
     pub mod_only: bool,
     pub instance_domain: String,
+    /// Hex color (eg: `#ff4500`)
+    #[serde(default)]
+    #[ts(optional)]
+    pub background_color: Option<String>,
+    /// Hex color (eg: `#ffffff`)
+    #[serde(default)]
+    #[ts(optional)]
+    pub text_color: Option<String>,
+    /// Whether users can replace the display name with their own text
+    #[serde(default)]
+    #[ts(optional)]
+    pub text_editable: Option<bool>,
+    /// Maximum length of the users' custom text, defaults to 64 characters
+    #[serde(default)]
+    #[ts(optional)]
+    pub max_length: Option<u32>,
+    /// Group of the community the flair belongs to
+    #[serde(default)]
+    #[ts(optional)]
+    pub group_name: Option<String>,
 }
 
 #[debug_handler]
 pub(crate) async fn put_community_flairs_api(
     State(state): State<AppState>,
     TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
-    Json(payload): Json<AddFlairJson>,
-) -> (StatusCode, String) {
-    match verify_mod(
-        &state.lemmy_port,
-        &state.docker,
-        &jwt.token(),
-        &payload.community_actor_id,
-        &state.lemmy_domain,
-        &payload.instance_domain,
-    )
-    .await
+    Json(mut payload): Json<AddFlairJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    for color in [&mut payload.background_color, &mut payload.text_color]
+        .into_iter()
+        .flatten()
     {
-        Ok(true) => (),
-        Ok(false) | Err(_) => return (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+        if !is_hex_color(color) {
+            return Err(ApiError::validation(format!(
+                "Invalid color '{color}', expected a hex color like '#ff4500'"
+            )));
+        }
+        color.make_ascii_lowercase();
     }
 
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return internal_error(e),
-    };
+    if let Some(max_length) = payload.max_length {
+        if max_length == 0 || max_length > TEXT_MAX_LENGTH_LIMIT {
+            return Err(ApiError::validation(format!(
+                "Maximum length must be between 1 and {TEXT_MAX_LENGTH_LIMIT}"
+            )));
+        }
+    }
+
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    state
+        .verifier
+        .check_actor(
+            ActorKind::Community,
+            &payload.community_actor_id,
+            jwt.token(),
+            &payload.instance_domain,
+        )
+        .await?;
+
+    if let Some(group_name) = &payload.group_name {
+        find_flair_group(&state, &payload.community_actor_id, group_name)
+            .await?
+            .ok_or_else(|| ApiError::not_found("Flair group not found"))?;
+    }
 
     let name = payload.name.clone();
+    let community_actor_id = payload.community_actor_id.clone();
 
-    if let Err(e) = conn
-        .interact(move |conn| return add_flair(&conn, &payload))
+    let before = state
+        .store
+        .get_flair(community_actor_id.clone(), name.clone())
         .await
-    {
-        return crate::internal_error(e);
-    }
+        .map_err(ApiError::db)?;
+
+    // The flair as it will be stored
+    let after = Flair::new(
+        payload.name.clone(),
+        payload.display_name.clone(),
+        payload.path.clone(),
+        community_actor_id.clone(),
+        payload.mod_only,
+        payload.background_color.clone(),
+        payload.text_color.clone(),
+        payload.text_editable.unwrap_or(false),
+        payload.max_length,
+        payload.group_name.clone(),
+    );
+    let action = if before.is_some() {
+        AuditAction::FlairUpdated
+    } else {
+        AuditAction::FlairCreated
+    };
+    let entry = NewAuditEntry {
+        flair_name: Some(name.clone()),
+        before: before.as_ref().and_then(to_audit_value),
+        after: to_audit_value(&after),
+        ..audit_entry(&verified, action, &community_actor_id)
+    };
+
+    state
+        .store
+        .add_flair(payload, entry)
+        .await
+        .map_err(ApiError::db)?;
 
-    (StatusCode::CREATED, format!("Flair '{name}' created"))
+    Ok((StatusCode::CREATED, format!("Flair '{name}' created")))
 }
 
 #[derive(Debug, Deserialize, Serialize, TS)]
@@ -203,48 +719,339 @@ pub(crate) async fn delete_community_flairs_api(
     State(state): State<AppState>,
     TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
     Json(payload): Json<DeleteFlairJson>,
-) -> (StatusCode, String) {
-    match verify_mod(
-        &state.lemmy_port,
-        &state.docker,
-        &jwt.token(),
-        &payload.community_actor_id,
-        &state.lemmy_domain,
-        &payload.instance_domain,
-    )
-    .await
-    {
-        Ok(true) => (),
-        Ok(false) | Err(_) => return (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let flair_name = payload.name.clone();
+    let community_actor_id = payload.community_actor_id.clone();
+
+    let before = state
+        .store
+        .get_flair(community_actor_id.clone(), flair_name.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    let entry = NewAuditEntry {
+        flair_name: Some(flair_name.clone()),
+        before: before.as_ref().and_then(to_audit_value),
+        ..audit_entry(&verified, AuditAction::FlairDeleted, &community_actor_id)
+    };
+
+    state
+        .store
+        .delete_flair(payload, entry)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok((
+        StatusCode::OK,
+        format!("Removed flair '{flair_name}' from community'{community_actor_id}'"),
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct ReorderFlairsJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    /// Names of every flair of the community, in the new order
+    pub names: Vec<String>,
+}
+
+/// Reorders the flairs of a community, the new order must list every flair exactly once
+#[debug_handler]
+pub(crate) async fn reorder_community_flairs_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<ReorderFlairsJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let unique: HashSet<&String> = payload.names.iter().collect();
+    if unique.len() != payload.names.len() {
+        return Err(ApiError::validation("Every flair must be listed only once"));
     }
 
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return internal_error(e),
+    let community_actor_id = payload.community_actor_id;
+    let before = state
+        .store
+        .get_community_flairs(GetFlairsJson {
+            community_actor_id: community_actor_id.clone(),
+            mod_only: Some(true),
+        })
+        .await
+        .map_err(ApiError::db)?
+        .into_iter()
+        .map(|f| f.name)
+        .collect::<Vec<_>>();
+
+    let entry = NewAuditEntry {
+        before: to_audit_value(&before),
+        after: to_audit_value(&payload.names),
+        ..audit_entry(&verified, AuditAction::FlairsReordered, &community_actor_id)
     };
 
-    let flair_name = payload.name.clone();
+    let reordered = state
+        .store
+        .reorder_flairs(community_actor_id.clone(), payload.names, entry)
+        .await
+        .map_err(ApiError::db)?;
+    if !reordered {
+        return Err(ApiError::validation(
+            "The new order must list every flair of the community",
+        ));
+    }
+
+    Ok((
+        StatusCode::OK,
+        format!("Reordered the flairs of community '{community_actor_id}'"),
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetFlairGroupsJson {
+    pub community_actor_id: String,
+}
+
+/// Lists the flair groups of a community, in the order chosen by the mods
+#[debug_handler]
+pub(crate) async fn get_flair_groups_api(
+    State(state): State<AppState>,
+    Query(payload): Query<GetFlairGroupsJson>,
+) -> Result<Json<Vec<FlairGroup>>, ApiError> {
+    let groups = state
+        .store
+        .get_flair_groups(payload.community_actor_id)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(groups))
+}
+
+/// Looks up a flair group of a community by name
+async fn find_flair_group(
+    state: &AppState,
+    community_actor_id: &str,
+    name: &str,
+) -> Result<Option<FlairGroup>, ApiError> {
+    Ok(state
+        .store
+        .get_flair_groups(community_actor_id.to_string())
+        .await
+        .map_err(ApiError::db)?
+        .into_iter()
+        .find(|g| g.name == name))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct AddFlairGroupJson {
+    pub name: String,
+    pub display_name: String,
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    /// Whether users may pick at most one flair of the group, false by default
+    #[serde(default)]
+    #[ts(optional)]
+    pub pick_one: Option<bool>,
+}
+
+/// Creates a flair group, or updates it if the community already has one with the same name
+#[debug_handler]
+pub(crate) async fn put_flair_group_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AddFlairGroupJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    if payload.name.trim().is_empty() || payload.display_name.trim().is_empty() {
+        return Err(ApiError::validation(
+            "The group's name and display name can't be empty",
+        ));
+    }
+
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    state
+        .verifier
+        .check_actor(
+            ActorKind::Community,
+            &payload.community_actor_id,
+            jwt.token(),
+            &payload.instance_domain,
+        )
+        .await?;
+
+    let name = payload.name.clone();
     let community_actor_id = payload.community_actor_id.clone();
 
-    if let Err(e) = conn
-        .interact(move |conn| {
-            return conn.execute(
-                r"DELETE FROM flairs WHERE name = ? AND community_actor_id = ?",
-                params![payload.name, payload.community_actor_id],
-            );
-        })
+    let before = find_flair_group(&state, &community_actor_id, &name).await?;
+
+    // The group as it will be stored
+    let after = FlairGroup {
+        name: name.clone(),
+        display_name: payload.display_name.clone(),
+        community_actor_id: community_actor_id.clone(),
+        pick_one: payload.pick_one.unwrap_or(false),
+    };
+    let action = if before.is_some() {
+        AuditAction::GroupUpdated
+    } else {
+        AuditAction::GroupCreated
+    };
+    let entry = NewAuditEntry {
+        before: before.as_ref().and_then(to_audit_value),
+        after: to_audit_value(&after),
+        ..audit_entry(&verified, action, &community_actor_id)
+    };
+
+    state
+        .store
+        .add_flair_group(payload, entry)
         .await
-    {
-        return crate::internal_error(e);
+        .map_err(ApiError::db)?;
+
+    Ok((StatusCode::CREATED, format!("Flair group '{name}' created")))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct DeleteFlairGroupJson {
+    pub name: String,
+    pub community_actor_id: String,
+    pub instance_domain: String,
+}
+
+/// Deletes a flair group, its flairs are kept and no longer belong to any group
+#[debug_handler]
+pub(crate) async fn delete_flair_group_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<DeleteFlairGroupJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let name = payload.name.clone();
+    let community_actor_id = payload.community_actor_id.clone();
+
+    let before = find_flair_group(&state, &community_actor_id, &name)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Flair group not found"))?;
+
+    let entry = NewAuditEntry {
+        before: to_audit_value(&before),
+        ..audit_entry(&verified, AuditAction::GroupDeleted, &community_actor_id)
+    };
+
+    state
+        .store
+        .delete_flair_group(payload, entry)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok((
+        StatusCode::OK,
+        format!("Removed flair group '{name}' from community '{community_actor_id}'"),
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct ReorderFlairGroupsJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    /// Names of every flair group of the community, in the new order
+    pub names: Vec<String>,
+}
+
+/// Reorders the flair groups of a community, the new order must list every group exactly once
+#[debug_handler]
+pub(crate) async fn reorder_flair_groups_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<ReorderFlairGroupsJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let unique: HashSet<&String> = payload.names.iter().collect();
+    if unique.len() != payload.names.len() {
+        return Err(ApiError::validation("Every group must be listed only once"));
+    }
+
+    let community_actor_id = payload.community_actor_id;
+    let before = state
+        .store
+        .get_flair_groups(community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?
+        .into_iter()
+        .map(|g| g.name)
+        .collect::<Vec<_>>();
+
+    let entry = NewAuditEntry {
+        before: to_audit_value(&before),
+        after: to_audit_value(&payload.names),
+        ..audit_entry(&verified, AuditAction::GroupsReordered, &community_actor_id)
+    };
+
+    let reordered = state
+        .store
+        .reorder_flair_groups(community_actor_id.clone(), payload.names, entry)
+        .await
+        .map_err(ApiError::db)?;
+    if !reordered {
+        return Err(ApiError::validation(
+            "The new order must list every flair group of the community",
+        ));
     }
 
-    (
+    Ok((
         StatusCode::OK,
-        format!("Removed flair '{flair_name}' from community'{community_actor_id}'"),
-    )
+        format!("Reordered the flair groups of community '{community_actor_id}'"),
+    ))
 }
 
-#[derive(Debug, Deserialize, Serialize, Default, TS)]
+#[derive(Debug, Deserialize, Serialize, Default, Clone, TS)]
 #[ts(export)]
 pub(crate) struct GetUserFlairJson {
     pub community_actor_id: String,
@@ -258,28 +1065,134 @@ pub(crate) async fn get_user_flair_api(
         community_actor_id,
         user_actor_id,
     }): Query<GetUserFlairJson>,
-) -> Result<Json<Option<Flair>>, StatusCode> {
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return Err(internal_error(e).0),
-    };
-
+) -> Result<Json<Option<Flair>>, ApiError> {
     let payload = GetUserFlairJson {
         community_actor_id,
         user_actor_id,
     };
 
-    let result = conn
-        .interact(move |conn| get_user_flair(conn, &payload).expect("Issue fetching user flairs"))
-        .await;
+    let flair = state
+        .store
+        .get_user_flair(payload)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(flair))
+}
 
-    match result {
-        Ok(flair) => Ok(Json(flair)),
-        Err(e) => {
-            eprintln!("{}", e); // Fixed the logging interpolation here as well
-            Err(crate::internal_error(e).0)
-        }
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct ListUserFlairsJson {
+    pub user_actor_id: String,
+    /// Only the communities sorted after this one, pass the community of the last flair received to get the next page
+    #[serde(default)]
+    #[ts(optional)]
+    pub after: Option<String>,
+    /// Maximum number of flairs, 50 by default and at most 100
+    #[serde(default)]
+    #[ts(optional)]
+    pub limit: Option<u32>,
+}
+
+/// Lists the flairs a user holds in every community, sorted by community (eg: for a profile page)
+#[debug_handler]
+pub(crate) async fn list_user_flairs_api(
+    State(state): State<AppState>,
+    Query(mut payload): Query<ListUserFlairsJson>,
+) -> Result<Json<Vec<Flair>>, ApiError> {
+    payload.limit = Some(page_limit(payload.limit)?);
+
+    let flairs = state
+        .store
+        .list_user_flairs(payload)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(flairs))
+}
+
+/// Maximum amount of (user, community) pairs that can be looked up with a single batch request
+const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Deserialize, Serialize, Default, TS)]
+#[ts(export)]
+pub(crate) struct GetUserFlairsJson {
+    /// Community shared by all the users in `user_actor_ids` (eg: a comment thread)
+    pub community_actor_id: Option<String>,
+    #[serde(default)]
+    pub user_actor_ids: Vec<String>,
+    /// Arbitrary (user, community) pairs (eg: the front page)
+    #[serde(default)]
+    pub pairs: Vec<GetUserFlairJson>,
+}
+
+/// Looks up the first flair of many users at once. The response maps each community to its users' flairs:
+/// `{ [community_actor_id]: { [user_actor_id]: Flair } }`
+#[debug_handler]
+pub(crate) async fn get_user_flairs_api(
+    State(state): State<AppState>,
+    Json(payload): Json<GetUserFlairsJson>,
+) -> Result<Json<HashMap<String, HashMap<String, Flair>>>, ApiError> {
+    let flairs = batch_user_flairs(&state, payload).await?;
+
+    Ok(Json(
+        flairs
+            .into_iter()
+            .map(|(community, users)| {
+                let users = users
+                    .into_iter()
+                    .filter_map(|(user, flairs)| Some((user, flairs.into_iter().next()?)))
+                    .collect();
+                (community, users)
+            })
+            .collect(),
+    ))
+}
+
+/// Looks up every flair of many users at once. The response maps each community to its users' flairs:
+/// `{ [community_actor_id]: { [user_actor_id]: Flair[] } }`, each list in the order it's displayed
+#[debug_handler]
+pub(crate) async fn get_user_flair_lists_api(
+    State(state): State<AppState>,
+    Json(payload): Json<GetUserFlairsJson>,
+) -> Result<Json<HashMap<String, HashMap<String, Vec<Flair>>>>, ApiError> {
+    Ok(Json(batch_user_flairs(&state, payload).await?))
+}
+
+/// The flairs of the users of a batch request, grouped by community first and by user second
+async fn batch_user_flairs(
+    state: &AppState,
+    payload: GetUserFlairsJson,
+) -> Result<HashMap<String, HashMap<String, Vec<Flair>>>, ApiError> {
+    let mut pairs = payload.pairs;
+    if let Some(community_actor_id) = payload.community_actor_id {
+        pairs.extend(
+            payload
+                .user_actor_ids
+                .into_iter()
+                .map(|user_actor_id| GetUserFlairJson {
+                    community_actor_id: community_actor_id.clone(),
+                    user_actor_id,
+                }),
+        );
+    } else if !payload.user_actor_ids.is_empty() {
+        return Err(ApiError::validation(
+            "user_actor_ids requires a community_actor_id",
+        ));
+    }
+
+    if pairs.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::validation(format!(
+            "At most {MAX_BATCH_SIZE} users can be looked up at once"
+        ))
+        .with_status(StatusCode::PAYLOAD_TOO_LARGE));
     }
+
+    state
+        .store
+        .get_user_flairs(pairs)
+        .await
+        .map_err(ApiError::db)
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, TS)]
@@ -289,6 +1202,7 @@ pub(crate) struct GetFlairsJson {
     pub mod_only: Option<bool>,
 }
 
+/// Lists the flairs of a community, each with the number of users holding it
 #[debug_handler]
 pub(crate) async fn get_community_flairs_api(
     State(state): State<AppState>,
@@ -296,50 +1210,704 @@ pub(crate) async fn get_community_flairs_api(
         community_actor_id,
         mod_only,
     }): Query<GetFlairsJson>,
-) -> Result<Json<Vec<Flair>>, StatusCode> {
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return Err(internal_error(e).0),
-    };
-
+) -> Result<Json<Vec<FlairListing>>, ApiError> {
     let payload = GetFlairsJson {
         community_actor_id,
         mod_only,
     };
 
-    let result = conn
-        .interact(move |conn| {
-            get_community_flairs(conn, &payload).expect("Issue getting community flairs")
+    Ok(Json(flair_listings(&state, payload).await?))
+}
+
+/// The flairs of a community, each with the number of users holding it
+async fn flair_listings(
+    state: &AppState,
+    payload: GetFlairsJson,
+) -> Result<Vec<FlairListing>, ApiError> {
+    let counts = state
+        .store
+        .count_flair_users(payload.community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    let flairs = state
+        .store
+        .get_community_flairs(payload)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(flairs
+        .into_iter()
+        .map(|flair| FlairListing {
+            user_count: counts.get(&flair.name).copied().unwrap_or(0),
+            flair,
+        })
+        .collect())
+}
+
+/// Lists the flairs of a community sorted into their groups, each with the number of users holding it
+#[debug_handler]
+pub(crate) async fn get_grouped_flairs_api(
+    State(state): State<AppState>,
+    Query(GetFlairsJson {
+        community_actor_id,
+        mod_only,
+    }): Query<GetFlairsJson>,
+) -> Result<Json<GroupedFlairs>, ApiError> {
+    let groups = state
+        .store
+        .get_flair_groups(community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    let flairs = flair_listings(
+        &state,
+        GetFlairsJson {
+            community_actor_id,
+            mod_only,
+        },
+    )
+    .await?;
+
+    let mut groups: Vec<FlairGroupListing> = groups
+        .into_iter()
+        .map(|group| FlairGroupListing {
+            group,
+            flairs: vec![],
         })
-        .await;
+        .collect();
+    let mut ungrouped: Vec<FlairListing> = vec![];
 
-    match result {
-        Ok(flairs) => Ok(Json(flairs)),
-        Err(e) => {
-            eprintln!("{}", e); // Fixed the logging interpolation here as well
-            Err(crate::internal_error(e).0)
+    // Flairs keep the order chosen by the mods within their group
+    for listing in flairs {
+        let group = listing
+            .flair
+            .group_name
+            .as_ref()
+            .and_then(|name| groups.iter_mut().find(|g| &g.group.name == name));
+        match group {
+            Some(group) => group.flairs.push(listing),
+            None => ungrouped.push(listing),
         }
     }
+
+    Ok(Json(GroupedFlairs { groups, ungrouped }))
+}
+
+/// Number of days covered by the community stats when the client doesn't ask for a range
+const STATS_DEFAULT_DAYS: u64 = 30;
+/// Maximum number of days covered by the community stats
+const STATS_MAX_DAYS: u64 = 366;
+/// Number of recent changes included in the community stats
+const STATS_RECENT_CHANGES: u32 = 10;
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetCommunityStatsJson {
+    pub community_actor_id: String,
+    /// First day of the assignments count (UTC), 30 days before `to` by default
+    #[serde(default)]
+    #[ts(optional)]
+    pub from: Option<NaiveDate>,
+    /// Last day of the assignments count (UTC), today by default
+    #[serde(default)]
+    #[ts(optional)]
+    pub to: Option<NaiveDate>,
+}
+
+/// Returns how flairs are used in a community, public so that communities can build leaderboards
+#[debug_handler]
+pub(crate) async fn get_community_stats_api(
+    State(state): State<AppState>,
+    Query(payload): Query<GetCommunityStatsJson>,
+) -> Result<Json<CommunityStats>, ApiError> {
+    let to = payload.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = match payload.from {
+        Some(from) => from,
+        None => to
+            .checked_sub_days(Days::new(STATS_DEFAULT_DAYS - 1))
+            .ok_or_else(|| ApiError::validation("Invalid date range"))?,
+    };
+    if from > to {
+        return Err(ApiError::validation("'from' must not be after 'to'"));
+    }
+    if from
+        .checked_add_days(Days::new(STATS_MAX_DAYS))
+        .is_some_and(|limit| limit <= to)
+    {
+        return Err(ApiError::validation(format!(
+            "Stats cover at most {STATS_MAX_DAYS} days"
+        )));
+    }
+
+    let community_actor_id = payload.community_actor_id;
+
+    let mut flairs = flair_listings(
+        &state,
+        GetFlairsJson {
+            community_actor_id: community_actor_id.clone(),
+            mod_only: Some(true),
+        },
+    )
+    .await?;
+    flairs.sort_by_key(|f| Reverse(f.user_count));
+
+    let daily = state
+        .store
+        .count_daily_assignments(community_actor_id.clone(), from, to)
+        .await
+        .map_err(ApiError::db)?;
+
+    let recent_changes = state
+        .store
+        .get_community_history(community_actor_id.clone(), STATS_RECENT_CHANGES)
+        .await
+        .map_err(ApiError::db)?;
+
+    let flaired_users = state
+        .store
+        .count_flaired_users(community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(CommunityStats {
+        community_actor_id,
+        flaired_users,
+        flairs,
+        assignments_per_day: from
+            .iter_days()
+            .take_while(|date| *date <= to)
+            .map(|date| DailyAssignments {
+                date,
+                assignments: daily.get(&date).copied().unwrap_or(0),
+            })
+            .collect(),
+        recent_changes,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetFlairMembersJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    pub flair_name: String,
+    /// `next_cursor` of the previous page
+    #[serde(default)]
+    #[ts(optional)]
+    pub cursor: Option<String>,
+    /// Maximum number of users, 50 by default and at most 100
+    #[serde(default)]
+    #[ts(optional)]
+    pub limit: Option<u32>,
+}
+
+/// Lists the users holding a flair, most recently assigned first, visible to the community mods
+#[debug_handler]
+pub(crate) async fn get_flair_members_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Query(payload): Query<GetFlairMembersJson>,
+) -> Result<Json<FlairMembersPage>, ApiError> {
+    state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let limit = page_limit(payload.limit)?;
+    let before = payload
+        .cursor
+        .as_deref()
+        .map(parse_members_cursor)
+        .transpose()?;
+
+    state
+        .store
+        .get_flair(
+            payload.community_actor_id.clone(),
+            payload.flair_name.clone(),
+        )
+        .await
+        .map_err(ApiError::db)?
+        .ok_or_else(|| ApiError::not_found("Flair not found"))?;
+
+    // One more member than asked tells whether there's a next page
+    let mut members = state
+        .store
+        .get_flair_members(
+            payload.community_actor_id,
+            payload.flair_name,
+            before,
+            limit + 1,
+        )
+        .await
+        .map_err(ApiError::db)?;
+
+    let mut next_cursor = None;
+    if members.len() > limit as usize {
+        members.truncate(limit as usize);
+        next_cursor = members.last().map(members_cursor);
+    }
+
+    Ok(Json(FlairMembersPage {
+        members,
+        next_cursor,
+    }))
+}
+
+/// Cursor pointing after a member, made of their assignment date and actor id
+fn members_cursor(member: &FlairMember) -> String {
+    format!(
+        "{},{}",
+        member.assigned_on.to_rfc3339(),
+        member.user_actor_id
+    )
+}
+
+fn parse_members_cursor(cursor: &str) -> Result<(DateTime<Utc>, String), ApiError> {
+    let invalid = || ApiError::validation("Invalid cursor");
+
+    let (date, user_actor_id) = cursor.split_once(',').ok_or_else(invalid)?;
+    let date = DateTime::parse_from_rfc3339(date).map_err(|_| invalid())?;
+
+    Ok((date.with_timezone(&Utc), user_actor_id.to_string()))
 }
 
 #[debug_handler]
 pub(crate) async fn get_community_list_api(
     State(state): State<AppState>,
-) -> Result<Json<Vec<String>>, StatusCode> {
-    let conn = match state.pool.get().await {
-        Ok(a) => a,
-        Err(e) => return Err(internal_error(e).0),
+) -> Result<Json<Vec<String>>, ApiError> {
+    let communities = state
+        .store
+        .get_community_list()
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(communities))
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, TS)]
+#[ts(export)]
+pub(crate) struct GetCommunitySettingsJson {
+    pub community_actor_id: String,
+}
+
+/// Returns the settings of a community, public so that frontends can hide what users aren't allowed to do
+#[debug_handler]
+pub(crate) async fn get_community_settings_api(
+    State(state): State<AppState>,
+    Query(payload): Query<GetCommunitySettingsJson>,
+) -> Result<Json<CommunitySettings>, ApiError> {
+    let settings = state
+        .store
+        .get_community_settings(payload.community_actor_id)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(settings))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct UpdateCommunitySettingsJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    pub flairs_enabled: bool,
+    pub self_assign: bool,
+    pub self_remove: bool,
+    pub require_approval: bool,
+    /// Maximum number of flairs a user can hold at once, unchanged if missing
+    #[serde(default)]
+    #[ts(optional)]
+    pub max_flairs: Option<u32>,
+}
+
+#[debug_handler]
+pub(crate) async fn put_community_settings_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<UpdateCommunitySettingsJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    if let Some(max_flairs) = payload.max_flairs {
+        if max_flairs == 0 || max_flairs > MAX_FLAIRS_LIMIT {
+            return Err(ApiError::validation(format!(
+                "Maximum flairs per user must be between 1 and {MAX_FLAIRS_LIMIT}"
+            )));
+        }
+    }
+
+    let community_actor_id = payload.community_actor_id.clone();
+    let before = state
+        .store
+        .get_community_settings(community_actor_id.clone())
+        .await
+        .map_err(ApiError::db)?;
+
+    let settings = CommunitySettings {
+        community_actor_id: payload.community_actor_id,
+        flairs_enabled: payload.flairs_enabled,
+        self_assign: payload.self_assign,
+        self_remove: payload.self_remove,
+        require_approval: payload.require_approval,
+        max_flairs: payload.max_flairs.unwrap_or(before.max_flairs),
     };
+    let entry = NewAuditEntry {
+        before: to_audit_value(&before),
+        after: to_audit_value(&settings),
+        ..audit_entry(&verified, AuditAction::SettingsUpdated, &community_actor_id)
+    };
+
+    state
+        .store
+        .set_community_settings(settings, entry)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok((
+        StatusCode::OK,
+        format!("Updated the settings of community '{community_actor_id}'"),
+    ))
+}
 
-    let result = conn
-        .interact(move |conn| get_community_list(conn).expect("Issue fetching community list"))
-        .await;
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetFlairRequestsJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+}
+
+/// Lists the requests waiting for a mod's decision in a community, oldest first
+#[debug_handler]
+pub(crate) async fn get_flair_requests_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Query(payload): Query<GetFlairRequestsJson>,
+) -> Result<Json<Vec<FlairRequest>>, ApiError> {
+    state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let requests = state
+        .store
+        .get_pending_requests(payload.community_actor_id)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(requests))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct DecideFlairRequestJson {
+    pub community_actor_id: String,
+    pub user_actor_id: String,
+    pub instance_domain: String,
+    /// Approving assigns the requested flair to the user, denying leaves their flair untouched
+    pub approved: bool,
+    /// Explanation shown to the user
+    #[serde(default)]
+    #[ts(optional)]
+    pub reason: Option<String>,
+}
+
+#[debug_handler]
+pub(crate) async fn decide_flair_request_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Json(mut payload): Json<DecideFlairRequestJson>,
+) -> Result<(StatusCode, String), ApiError> {
+    let verified = state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
 
-    match result {
-        Ok(flair) => Ok(Json(flair)),
-        Err(e) => {
-            eprintln!("{}", e);
-            Err(crate::internal_error(e).0)
+    payload.reason = payload
+        .reason
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty());
+
+    let request = state
+        .store
+        .get_flair_request(
+            payload.community_actor_id.clone(),
+            payload.user_actor_id.clone(),
+        )
+        .await
+        .map_err(ApiError::db)?
+        .filter(|r| r.status == RequestStatus::Pending)
+        .ok_or_else(|| ApiError::not_found("Request not found"))?;
+
+    let mut replaced = vec![];
+    if payload.approved {
+        let settings = state
+            .store
+            .get_community_settings(payload.community_actor_id.clone())
+            .await
+            .map_err(ApiError::db)?;
+
+        if !settings.flairs_enabled {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Flairs are disabled in this community",
+            ));
         }
+
+        // The flair might have been deleted or edited while the request was waiting
+        let flair = state
+            .store
+            .get_flair(
+                request.community_actor_id.clone(),
+                request.flair_name.clone(),
+            )
+            .await
+            .map_err(ApiError::db)?
+            .ok_or_else(|| ApiError::not_found("The requested flair doesn't exist anymore"))?;
+
+        if let Some(text) = &request.custom_text {
+            validate_custom_text(text, &flair).map_err(ApiError::validation)?;
+        }
+
+        // The user's flairs might have changed as well, the request is checked as if they were assigning it
+        replaced = replaced_flairs(
+            &state,
+            &request.user_actor_id,
+            &flair,
+            &settings,
+            Role::Owner,
+        )
+        .await?;
     }
+
+    let approved = payload.approved;
+    let user_name = payload.user_actor_id.clone();
+
+    // The request as it will be stored once decided
+    let (status, action) = if approved {
+        (RequestStatus::Approved, AuditAction::RequestApproved)
+    } else {
+        (RequestStatus::Denied, AuditAction::RequestDenied)
+    };
+    let after = FlairRequest {
+        status,
+        reason: payload.reason.clone(),
+        decided_on: Some(Utc::now()),
+        decided_by: Some(verified.actor_id.clone()),
+        ..request.clone()
+    };
+    let entry = NewAuditEntry {
+        user_actor_id: Some(user_name.clone()),
+        flair_name: Some(request.flair_name.clone()),
+        before: to_audit_value(&request),
+        after: to_audit_value(&after),
+        ..audit_entry(&verified, action, &request.community_actor_id)
+    };
+
+    let decided = state
+        .store
+        .decide_flair_request(payload, replaced, verified.actor_id.clone(), entry)
+        .await
+        .map_err(ApiError::db)?;
+    if !decided {
+        return Err(ApiError::not_found("Request not found"));
+    }
+
+    Ok((
+        StatusCode::OK,
+        format!(
+            "{} the request of user '{user_name}'",
+            if approved { "Approved" } else { "Denied" }
+        ),
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetFlairRequestJson {
+    pub community_actor_id: String,
+    pub user_actor_id: String,
+    pub instance_domain: String,
+}
+
+/// Returns the latest flair request of a user in a community, visible to the user and the community mods
+#[debug_handler]
+pub(crate) async fn get_flair_request_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Query(payload): Query<GetFlairRequestJson>,
+) -> Result<Json<Option<FlairRequest>>, ApiError> {
+    state
+        .verifier
+        .verify_user(
+            jwt.token(),
+            &payload.user_actor_id,
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    let request = state
+        .store
+        .get_flair_request(payload.community_actor_id, payload.user_actor_id)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(request))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetAuditLogJson {
+    pub community_actor_id: String,
+    pub instance_domain: String,
+    #[serde(default)]
+    #[ts(optional)]
+    pub action: Option<AuditAction>,
+    /// Only the changes made by this user
+    #[serde(default)]
+    #[ts(optional)]
+    pub actor_id: Option<String>,
+    /// Only the changes to the flair of this user
+    #[serde(default)]
+    #[ts(optional)]
+    pub user_actor_id: Option<String>,
+    #[serde(default)]
+    #[ts(optional)]
+    pub flair_name: Option<String>,
+    /// Only the entries older than this id, pass the id of the last entry received to get the next page
+    #[serde(default)]
+    #[ts(optional, type = "number")]
+    pub before: Option<i64>,
+    /// Maximum number of entries, 50 by default and at most 100
+    #[serde(default)]
+    #[ts(optional)]
+    pub limit: Option<u32>,
 }
+
+/// Lists the changes made to the flairs of a community, newest first, visible to the community mods
+#[debug_handler]
+pub(crate) async fn get_audit_log_api(
+    State(state): State<AppState>,
+    TypedHeader(jwt): TypedHeader<Authorization<Bearer>>,
+    Query(mut payload): Query<GetAuditLogJson>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    state
+        .verifier
+        .verify_mod(
+            jwt.token(),
+            &payload.community_actor_id,
+            &payload.instance_domain,
+        )
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    payload.limit = Some(page_limit(payload.limit)?);
+
+    let entries = state
+        .store
+        .get_audit_log(payload)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub(crate) struct GetUserFlairHistoryJson {
+    pub community_actor_id: String,
+    pub user_actor_id: String,
+    /// Only the entries older than this id, pass the id of the last entry received to get the next page
+    #[serde(default)]
+    #[ts(optional, type = "number")]
+    pub before: Option<i64>,
+    /// Maximum number of entries, 50 by default and at most 100
+    #[serde(default)]
+    #[ts(optional)]
+    pub limit: Option<u32>,
+}
+
+/// Lists the flairs a user received or lost in a community, newest first.
+/// Public like the flairs themselves, so that frontends can show since when a user has their flair.
+#[debug_handler]
+pub(crate) async fn get_user_flair_history_api(
+    State(state): State<AppState>,
+    Query(mut payload): Query<GetUserFlairHistoryJson>,
+) -> Result<Json<Vec<UserFlairHistoryEntry>>, ApiError> {
+    payload.limit = Some(page_limit(payload.limit)?);
+
+    let history = state
+        .store
+        .get_user_flair_history(payload)
+        .await
+        .map_err(ApiError::db)?;
+
+    Ok(Json(history))
+}
+
+/// Audit entry of a change made by a verified user, targets and values being filled by the caller
+fn audit_entry(
+    verified: &Verified,
+    action: AuditAction,
+    community_actor_id: &str,
+) -> NewAuditEntry {
+    NewAuditEntry {
+        actor_id: verified.actor_id.clone(),
+        as_admin: verified.role == Role::Admin,
+        action,
+        community_actor_id: community_actor_id.to_string(),
+        user_actor_id: None,
+        flair_name: None,
+        before: None,
+        after: None,
+    }
+}
+
+fn to_audit_value<T: Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+/// The flairs a user has in a community, as they're displayed
+async fn current_user_flairs(
+    state: &AppState,
+    community_actor_id: &str,
+    user_actor_id: &str,
+) -> Result<Vec<Flair>, ApiError> {
+    state
+        .store
+        .get_user_flair_list(GetUserFlairJson {
+            community_actor_id: community_actor_id.to_string(),
+            user_actor_id: user_actor_id.to_string(),
+        })
+        .await
+        .map_err(ApiError::db)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,952 @@
+//! Handler tests, backed by the in memory store and by a fake Lemmy instance verifying the JWTs
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use axum::{response::IntoResponse, routing, Router};
+use serde_json::{json, Value};
+
+use super::*;
+use crate::config::Config;
+use crate::db;
+use crate::verify::Verifier;
+
+const DOMAIN: &str = "lemmy.test";
+const COMMUNITY: &str = "https://lemmy.test/c/flairs";
+const MOD: &str = "https://lemmy.test/u/mod";
+const USER: &str = "https://lemmy.test/u/user";
+const ADMIN: &str = "https://lemmy.test/u/admin";
+/// Person the fake instance doesn't know yet, but can fetch from its instance
+const NEWCOMER: &str = "https://remote.test/u/newcomer";
+
+/// Answers like Lemmy 0.19 does, the JWTs being the names of the users
+async fn site(
+    axum::extract::State(lookups): axum::extract::State<Arc<AtomicUsize>>,
+    headers: HeaderMap,
+) -> Result<axum::Json<Value>, StatusCode> {
+    lookups.fetch_add(1, Ordering::SeqCst);
+    let jwt = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let (actor_id, moderates) = match jwt {
+        Some("mod") => (MOD, vec![COMMUNITY]),
+        Some("user") => (USER, vec![]),
+        Some("admin") => (ADMIN, vec![]),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    Ok(axum::Json(json!({
+        "version": "0.19.3",
+        "my_user": {
+            "moderates": moderates
+                .iter()
+                .map(|actor_id| json!({ "community": { "actor_id": actor_id } }))
+                .collect::<Vec<_>>(),
+            "local_user_view": {
+                "person": { "actor_id": actor_id },
+                "local_user": { "admin": actor_id == ADMIN },
+            },
+        },
+    })))
+}
+
+/// Finds the communities the instance knows, by `name@domain`
+async fn community(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<axum::Json<Value>, StatusCode> {
+    match params.get("name").map(String::as_str) {
+        Some("flairs@lemmy.test") => Ok(axum::Json(
+            json!({ "community_view": { "community": { "actor_id": COMMUNITY } } }),
+        )),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Finds the people the instance knows, by `name@domain`
+async fn user(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<axum::Json<Value>, StatusCode> {
+    let actor_id = match params.get("username").map(String::as_str) {
+        Some("mod@lemmy.test") => MOD,
+        Some("user@lemmy.test") => USER,
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    Ok(axum::Json(
+        json!({ "person_view": { "person": { "actor_id": actor_id } } }),
+    ))
+}
+
+/// Fetches remote people, only on behalf of the local users
+async fn resolve_object(
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<axum::Json<Value>, StatusCode> {
+    let authenticated = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| ["Bearer mod", "Bearer user"].contains(&h));
+    if !authenticated {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match params.get("q").map(String::as_str) {
+        Some(NEWCOMER) => Ok(axum::Json(
+            json!({ "person": { "person": { "actor_id": NEWCOMER } } }),
+        )),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Starts a fake local Lemmy instance, returns its port and the number of users it looked up so far
+fn start_lemmy() -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let lemmy = Router::new()
+        .route("/api/v3/site", routing::get(site))
+        .route("/api/v3/community", routing::get(community))
+        .route("/api/v3/user", routing::get(user))
+        .route("/api/v3/resolve_object", routing::get(resolve_object))
+        .with_state(lookups.clone());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(lemmy.into_make_service()),
+    );
+
+    (port, lookups)
+}
+
+/// Configuration of a server using the fake Lemmy instance, with users cached for `auth_cache_ttl` seconds
+fn test_config(lemmy_port: u16, auth_cache_ttl: u64) -> Config {
+    let internal_url = format!("http://127.0.0.1:{lemmy_port}");
+    let auth_cache_ttl = auth_cache_ttl.to_string();
+
+    Config::load_with_env(None, &|var| match var {
+        "LEMMY_DOMAIN" => Some(DOMAIN.to_string()),
+        "LEMMY_INTERNAL_URL" => Some(internal_url.clone()),
+        "LEMMY_API_VERSION" => Some("0.19".to_string()),
+        "AUTH_CACHE_TTL" => Some(auth_cache_ttl.clone()),
+        _ => None,
+    })
+    .unwrap()
+}
+
+fn state_with_cache(lemmy_port: u16, auth_cache_ttl: u64) -> AppState {
+    AppState {
+        store: db::connect(":memory:").unwrap(),
+        verifier: Arc::new(Verifier::new(&test_config(lemmy_port, auth_cache_ttl))),
+    }
+}
+
+/// Starts a fake local Lemmy instance and returns the state of a server using it, with an empty in memory store
+fn test_state() -> AppState {
+    state_with_cache(start_lemmy().0, 60)
+}
+
+/// Same as [`test_state`], checking the actors exist before writing flairs
+fn validating_state() -> AppState {
+    let mut config = test_config(start_lemmy().0, 60);
+    config.lemmy.validate_actors = true;
+
+    AppState {
+        store: db::connect(":memory:").unwrap(),
+        verifier: Arc::new(Verifier::new(&config)),
+    }
+}
+
+/// Status code of a handler's response, whether it succeeded or not
+fn status(response: impl IntoResponse) -> StatusCode {
+    response.into_response().status()
+}
+
+fn jwt(token: &str) -> TypedHeader<Authorization<Bearer>> {
+    TypedHeader(Authorization::bearer(token).unwrap())
+}
+
+/// Flair open to everyone, without colors
+fn new_flair(name: &str) -> AddFlairJson {
+    AddFlairJson {
+        name: name.to_string(),
+        display_name: name.to_string(),
+        path: None,
+        community_actor_id: COMMUNITY.to_string(),
+        mod_only: false,
+        instance_domain: DOMAIN.to_string(),
+        background_color: None,
+        text_color: None,
+        text_editable: None,
+        max_length: None,
+        group_name: None,
+    }
+}
+
+async fn put_flair(state: &AppState, token: &str, flair: AddFlairJson) -> StatusCode {
+    status(put_community_flairs_api(State(state.clone()), jwt(token), Json(flair)).await)
+}
+
+async fn add_flair(state: &AppState, token: &str, name: &str) -> StatusCode {
+    put_flair(state, token, new_flair(name)).await
+}
+
+/// Assignment of a flair without custom text
+fn new_assignment(user_actor_id: &str, flair_name: &str) -> AddUserFlairJson {
+    AddUserFlairJson {
+        user_actor_id: user_actor_id.to_string(),
+        community_actor_id: COMMUNITY.to_string(),
+        flair_name: flair_name.to_string(),
+        instance_domain: DOMAIN.to_string(),
+        custom_text: None,
+    }
+}
+
+async fn put_assignment(state: &AppState, token: &str, assignment: AddUserFlairJson) -> StatusCode {
+    status(put_user_flair_api(State(state.clone()), jwt(token), Json(assignment)).await)
+}
+
+/// Error code of a rejected assignment
+async fn assignment_error(
+    state: &AppState,
+    token: &str,
+    assignment: AddUserFlairJson,
+) -> ErrorCode {
+    put_user_flair_api(State(state.clone()), jwt(token), Json(assignment))
+        .await
+        .unwrap_err()
+        .error
+}
+
+async fn assign(
+    state: &AppState,
+    token: &str,
+    user_actor_id: &str,
+    flair_name: &str,
+) -> StatusCode {
+    put_assignment(state, token, new_assignment(user_actor_id, flair_name)).await
+}
+
+async fn remove(state: &AppState, token: &str, user_actor_id: &str) -> StatusCode {
+    status(
+        delete_user_api(
+            State(state.clone()),
+            jwt(token),
+            Json(DeleteUserFlairJson {
+                user_actor_id: user_actor_id.to_string(),
+                community_actor_id: COMMUNITY.to_string(),
+                instance_domain: DOMAIN.to_string(),
+            }),
+        )
+        .await,
+    )
+}
+
+/// Flair held by a user, if any
+async fn user_flair_details(state: &AppState, user_actor_id: &str) -> Option<Flair> {
+    state
+        .store
+        .get_user_flair(GetUserFlairJson {
+            community_actor_id: COMMUNITY.to_string(),
+            user_actor_id: user_actor_id.to_string(),
+        })
+        .await
+        .unwrap()
+}
+
+/// Name of the flair held by a user, if any
+async fn user_flair(state: &AppState, user_actor_id: &str) -> Option<String> {
+    user_flair_details(state, user_actor_id)
+        .await
+        .map(|f| f.name)
+}
+
+#[tokio::test]
+async fn users_assign_their_own_flair() {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+    add_flair(&state, "mod", "based").await;
+
+    assert_eq!(
+        assign(&state, "user", USER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(user_flair(&state, USER).await.as_deref(), Some("auth"));
+
+    assign(&state, "user", USER, "based").await;
+    assert_eq!(user_flair(&state, USER).await.as_deref(), Some("based"));
+
+    assert_eq!(remove(&state, "user", USER).await, StatusCode::OK);
+    assert_eq!(user_flair(&state, USER).await, None);
+}
+
+#[tokio::test]
+async fn users_cant_assign_flairs_to_others() {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+
+    assert_eq!(
+        assign(&state, "user", MOD, "auth").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        assign(&state, "stranger", USER, "auth").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(user_flair(&state, USER).await, None);
+
+    // Mods can change everyone's flair
+    assert_eq!(
+        assign(&state, "mod", USER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(remove(&state, "user", MOD).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn only_mods_manage_community_flairs() {
+    let state = test_state();
+
+    assert_eq!(
+        add_flair(&state, "user", "auth").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(add_flair(&state, "mod", "auth").await, StatusCode::CREATED);
+    assert_eq!(
+        state
+            .store
+            .get_community_flairs(GetFlairsJson {
+                community_actor_id: COMMUNITY.to_string(),
+                mod_only: None,
+            })
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn local_admins_act_as_mods() {
+    let state = test_state();
+    assert_eq!(
+        add_flair(&state, "admin", "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        assign(&state, "admin", USER, "auth").await,
+        StatusCode::CREATED
+    );
+
+    let mut config = test_config(start_lemmy().0, 60);
+    config.lemmy.admin_override = false;
+    let without_override = AppState {
+        verifier: Arc::new(Verifier::new(&config)),
+        ..state
+    };
+    assert_eq!(
+        add_flair(&without_override, "admin", "based").await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn flair_colors_are_validated() {
+    let state = test_state();
+
+    let status = put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            background_color: Some("red".to_string()),
+            ..new_flair("auth")
+        },
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let status = put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            background_color: Some("#FF4500".to_string()),
+            text_color: Some("#fff".to_string()),
+            ..new_flair("auth")
+        },
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let flairs = state
+        .store
+        .get_community_flairs(GetFlairsJson {
+            community_actor_id: COMMUNITY.to_string(),
+            mod_only: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(flairs[0].background_color.as_deref(), Some("#ff4500"));
+    assert_eq!(flairs[0].text_color.as_deref(), Some("#fff"));
+}
+
+#[tokio::test]
+async fn custom_text_is_validated() {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+    put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            text_editable: Some(true),
+            max_length: Some(12),
+            ..new_flair("lib")
+        },
+    )
+    .await;
+
+    let with_text = |flair_name: &str, text: &str| AddUserFlairJson {
+        custom_text: Some(text.to_string()),
+        ..new_assignment(USER, flair_name)
+    };
+
+    // Only editable flairs accept a custom text, within their maximum length
+    for (flair_name, text) in [
+        ("auth", "Authoritarian"),
+        ("lib", "Libertarian socialist"),
+        ("lib", "Liber\ntarian"),
+    ] {
+        assert_eq!(
+            put_assignment(&state, "user", with_text(flair_name, text)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    assert_eq!(user_flair(&state, USER).await, None);
+
+    assert_eq!(
+        put_assignment(&state, "user", with_text("lib", " Libertarian ")).await,
+        StatusCode::CREATED
+    );
+    let flair = user_flair_details(&state, USER).await.unwrap();
+    assert_eq!(flair.display_name, "Libertarian");
+
+    // Mods can't set lengths out of bounds
+    let status = put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            max_length: Some(0),
+            ..new_flair("lib")
+        },
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+async fn set_settings(state: &AppState, token: &str, settings: CommunitySettings) -> StatusCode {
+    status(
+        put_community_settings_api(
+            State(state.clone()),
+            jwt(token),
+            Json(UpdateCommunitySettingsJson {
+                community_actor_id: COMMUNITY.to_string(),
+                instance_domain: DOMAIN.to_string(),
+                flairs_enabled: settings.flairs_enabled,
+                self_assign: settings.self_assign,
+                self_remove: settings.self_remove,
+                require_approval: settings.require_approval,
+                max_flairs: Some(settings.max_flairs),
+            }),
+        )
+        .await,
+    )
+}
+
+#[tokio::test]
+async fn settings_restrict_users() {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+    let defaults = CommunitySettings::new(COMMUNITY.to_string());
+
+    assert_eq!(
+        set_settings(&state, "user", defaults.clone()).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // Mods aren't bound by the self-service rules
+    let mods_only = CommunitySettings {
+        self_assign: false,
+        self_remove: false,
+        ..defaults.clone()
+    };
+    assert_eq!(set_settings(&state, "mod", mods_only).await, StatusCode::OK);
+    assert_eq!(
+        assign(&state, "user", USER, "auth").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        assign(&state, "mod", USER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(remove(&state, "user", USER).await, StatusCode::FORBIDDEN);
+    assert_eq!(remove(&state, "mod", USER).await, StatusCode::OK);
+
+    let approval = CommunitySettings {
+        require_approval: true,
+        ..defaults.clone()
+    };
+    set_settings(&state, "mod", approval).await;
+    assert_eq!(
+        assign(&state, "user", USER, "auth").await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(user_flair(&state, USER).await, None);
+
+    // Nobody gets flairs once they're disabled, but users can still remove theirs
+    let disabled = CommunitySettings {
+        flairs_enabled: false,
+        ..defaults
+    };
+    assign(&state, "mod", USER, "auth").await;
+    set_settings(&state, "mod", disabled).await;
+    assert_eq!(
+        assign(&state, "mod", MOD, "auth").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(remove(&state, "user", USER).await, StatusCode::OK);
+}
+
+async fn decide(state: &AppState, token: &str, approved: bool) -> StatusCode {
+    status(
+        decide_flair_request_api(
+            State(state.clone()),
+            jwt(token),
+            Json(DecideFlairRequestJson {
+                community_actor_id: COMMUNITY.to_string(),
+                user_actor_id: USER.to_string(),
+                instance_domain: DOMAIN.to_string(),
+                approved,
+                reason: None,
+            }),
+        )
+        .await,
+    )
+}
+
+#[tokio::test]
+async fn requests_wait_for_approval() {
+    let state = test_state();
+    let editable = |text_editable: bool| AddFlairJson {
+        text_editable: Some(text_editable),
+        ..new_flair("auth")
+    };
+    put_flair(&state, "mod", editable(true)).await;
+    set_settings(
+        &state,
+        "mod",
+        CommunitySettings {
+            require_approval: true,
+            ..CommunitySettings::new(COMMUNITY.to_string())
+        },
+    )
+    .await;
+
+    let with_text = AddUserFlairJson {
+        custom_text: Some("Auth".to_string()),
+        ..new_assignment(USER, "auth")
+    };
+    assert_eq!(
+        put_assignment(&state, "user", with_text.clone()).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(decide(&state, "user", true).await, StatusCode::UNAUTHORIZED);
+
+    // The custom text is checked again, the flair might have been locked in the meantime
+    put_flair(&state, "mod", editable(false)).await;
+    assert_eq!(decide(&state, "mod", true).await, StatusCode::BAD_REQUEST);
+    assert_eq!(user_flair(&state, USER).await, None);
+
+    put_flair(&state, "mod", editable(true)).await;
+    assert_eq!(decide(&state, "mod", true).await, StatusCode::OK);
+    let flair = user_flair_details(&state, USER).await.unwrap();
+    assert_eq!(flair.display_name, "Auth");
+
+    // Decided requests leave the queue, and unknown flairs can't be requested
+    assert_eq!(decide(&state, "mod", true).await, StatusCode::NOT_FOUND);
+    assert_eq!(
+        put_assignment(&state, "user", new_assignment(USER, "based")).await,
+        StatusCode::NOT_FOUND
+    );
+
+    // Requests for flairs deleted in the meantime can't be approved
+    add_flair(&state, "mod", "gone").await;
+    put_assignment(&state, "user", new_assignment(USER, "gone")).await;
+    let verified = Verified {
+        actor_id: MOD.to_string(),
+        role: Role::Mod,
+    };
+    state
+        .store
+        .delete_flair(
+            DeleteFlairJson {
+                name: "gone".to_string(),
+                community_actor_id: COMMUNITY.to_string(),
+                instance_domain: DOMAIN.to_string(),
+            },
+            audit_entry(&verified, AuditAction::FlairDeleted, COMMUNITY),
+        )
+        .await
+        .unwrap();
+    assert_eq!(decide(&state, "mod", true).await, StatusCode::NOT_FOUND);
+    assert_eq!(decide(&state, "mod", false).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_flairs_are_rejected() {
+    let state = test_state();
+
+    for token in ["user", "mod"] {
+        assert_eq!(
+            assignment_error(&state, token, new_assignment(USER, "nope")).await,
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            assign(&state, token, USER, "nope").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+    assert_eq!(user_flair(&state, USER).await, None);
+}
+
+#[tokio::test]
+async fn mod_only_flairs_are_assigned_by_mods() {
+    let state = test_state();
+    put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            mod_only: true,
+            ..new_flair("based")
+        },
+    )
+    .await;
+
+    assert_eq!(
+        assignment_error(&state, "user", new_assignment(USER, "based")).await,
+        ErrorCode::ModOnly
+    );
+    assert_eq!(
+        assign(&state, "user", USER, "based").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(user_flair(&state, USER).await, None);
+
+    assert_eq!(
+        assign(&state, "mod", USER, "based").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(user_flair(&state, USER).await.as_deref(), Some("based"));
+}
+
+#[tokio::test]
+async fn errors_are_structured() {
+    let state = test_state();
+
+    let err = put_user_flair_api(
+        State(state.clone()),
+        jwt("user"),
+        Json(new_assignment(USER, "nope")),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        serde_json::to_value(&err).unwrap(),
+        json!({ "error": "not_found", "message": "Flair not found" })
+    );
+    assert_eq!(status(err), StatusCode::NOT_FOUND);
+
+    assert_eq!(
+        assignment_error(&state, "stranger", new_assignment(USER, "auth")).await,
+        ErrorCode::Unauthorized
+    );
+
+    // Nothing listens on the port anymore once the listener is dropped
+    let lemmy_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let unreachable = AppState {
+        verifier: Arc::new(Verifier::new(&test_config(lemmy_port, 0))),
+        ..state
+    };
+    assert_eq!(
+        assignment_error(&unreachable, "user", new_assignment(USER, "auth")).await,
+        ErrorCode::LemmyUnreachable
+    );
+    assert_eq!(
+        assign(&unreachable, "user", USER, "auth").await,
+        StatusCode::BAD_GATEWAY
+    );
+}
+
+#[tokio::test]
+async fn lemmy_lookups_are_cached() {
+    let (lemmy_port, lookups) = start_lemmy();
+    let state = state_with_cache(lemmy_port, 60);
+
+    add_flair(&state, "mod", "auth").await;
+    add_flair(&state, "mod", "based").await;
+    assign(&state, "mod", USER, "auth").await;
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+    // Denied requests ask Lemmy again, in case the cached user is stale
+    assign(&state, "user", USER, "auth").await;
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        assign(&state, "user", MOD, "auth").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+    let (lemmy_port, lookups) = start_lemmy();
+    let uncached = state_with_cache(lemmy_port, 0);
+    add_flair(&uncached, "mod", "auth").await;
+    add_flair(&uncached, "mod", "based").await;
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn unknown_users_are_rejected() {
+    let state = validating_state();
+    add_flair(&state, "mod", "auth").await;
+
+    let ghost = "https://lemmy.test/u/ghost";
+    assert_eq!(
+        assignment_error(&state, "mod", new_assignment(ghost, "auth")).await,
+        ErrorCode::UnknownActor
+    );
+    assert_eq!(user_flair(&state, ghost).await, None);
+
+    assert_eq!(
+        assign(&state, "mod", USER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(user_flair(&state, USER).await.as_deref(), Some("auth"));
+}
+
+#[tokio::test]
+async fn new_remote_users_are_resolved() {
+    let state = validating_state();
+    add_flair(&state, "mod", "auth").await;
+
+    // The local instance fetches the user on behalf of the mod
+    assert_eq!(
+        assign(&state, "mod", NEWCOMER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(user_flair(&state, NEWCOMER).await.as_deref(), Some("auth"));
+}
+
+/// Audit log of the community as seen by a user, newest entries first
+async fn audit_log(state: &AppState, token: &str) -> Result<Vec<AuditEntry>, ApiError> {
+    let Json(entries) = get_audit_log_api(
+        State(state.clone()),
+        jwt(token),
+        Query(GetAuditLogJson {
+            community_actor_id: COMMUNITY.to_string(),
+            instance_domain: DOMAIN.to_string(),
+            action: None,
+            actor_id: None,
+            user_actor_id: None,
+            flair_name: None,
+            before: None,
+            limit: None,
+        }),
+    )
+    .await?;
+
+    Ok(entries)
+}
+
+#[tokio::test]
+async fn changes_are_audited() {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+    add_flair(&state, "mod", "based").await;
+    assign(&state, "user", USER, "auth").await;
+    assign(&state, "mod", USER, "based").await;
+    remove(&state, "user", USER).await;
+
+    let log = audit_log(&state, "mod").await.unwrap();
+    let actions: Vec<AuditAction> = log.iter().map(|e| e.action).collect();
+    assert_eq!(
+        actions,
+        [
+            AuditAction::UserFlairRemoved,
+            AuditAction::UserFlairAssigned,
+            AuditAction::UserFlairAssigned,
+            AuditAction::FlairCreated,
+            AuditAction::FlairCreated,
+        ]
+    );
+    assert_eq!(log[0].actor_id, USER);
+    assert_eq!(log[0].flair_name.as_deref(), Some("based"));
+    assert_eq!(log[1].actor_id, MOD);
+    // Every flair the user had is recorded
+    assert_eq!(log[1].before.as_ref().unwrap()[0]["name"], "auth");
+    assert_eq!(log[1].after.as_ref().unwrap()["name"], "based");
+    assert_eq!(log[4].after.as_ref().unwrap()["name"], "auth");
+
+    // Rejected changes aren't recorded
+    assert_eq!(
+        assign(&state, "user", MOD, "auth").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(audit_log(&state, "mod").await.unwrap().len(), 5);
+
+    // Only mods can read the log
+    let err = audit_log(&state, "user").await.unwrap_err();
+    assert_eq!(err.error, ErrorCode::Unauthorized);
+}
+
+/// Replaces every flair of a user through the v2 endpoint
+async fn set_flairs(state: &AppState, token: &str, flair_names: &[&str]) -> StatusCode {
+    status(
+        put_user_flair_list_api(
+            State(state.clone()),
+            jwt(token),
+            Json(SetUserFlairsJson {
+                user_actor_id: USER.to_string(),
+                community_actor_id: COMMUNITY.to_string(),
+                instance_domain: DOMAIN.to_string(),
+                flairs: flair_names
+                    .iter()
+                    .map(|name| UserFlairChoice {
+                        flair_name: name.to_string(),
+                        custom_text: None,
+                    })
+                    .collect(),
+            }),
+        )
+        .await,
+    )
+}
+
+/// Names of the flairs held by the user, in the order they're displayed
+async fn user_flair_list(state: &AppState) -> Vec<String> {
+    let Json(flairs) = get_user_flair_list_api(
+        State(state.clone()),
+        Query(GetUserFlairJson {
+            community_actor_id: COMMUNITY.to_string(),
+            user_actor_id: USER.to_string(),
+        }),
+    )
+    .await
+    .unwrap();
+
+    flairs.into_iter().map(|f| f.name).collect()
+}
+
+/// Community where users hold up to two flairs, one of them being reserved to mods
+async fn multi_flair_state() -> AppState {
+    let state = test_state();
+    add_flair(&state, "mod", "auth").await;
+    add_flair(&state, "mod", "based").await;
+    put_flair(
+        &state,
+        "mod",
+        AddFlairJson {
+            mod_only: true,
+            ..new_flair("helper")
+        },
+    )
+    .await;
+    set_settings(
+        &state,
+        "mod",
+        CommunitySettings {
+            max_flairs: 2,
+            ..CommunitySettings::new(COMMUNITY.to_string())
+        },
+    )
+    .await;
+
+    state
+}
+
+#[tokio::test]
+async fn users_keep_the_flairs_mods_gave_them() {
+    let state = multi_flair_state().await;
+    assign(&state, "mod", USER, "helper").await;
+
+    // v1 assignments replace the self-chosen flair only
+    assert_eq!(
+        assign(&state, "user", USER, "auth").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        assign(&state, "user", USER, "based").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(user_flair_list(&state).await, ["helper", "based"]);
+
+    // Neither removing every flair nor leaving the mod's one out of the list works for users
+    assert_eq!(remove(&state, "user", USER).await, StatusCode::FORBIDDEN);
+    assert_eq!(
+        set_flairs(&state, "user", &["auth"]).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(user_flair_list(&state).await, ["helper", "based"]);
+
+    // Users can still reorder their flairs and swap the ones they picked
+    assert_eq!(
+        set_flairs(&state, "user", &["auth", "helper"]).await,
+        StatusCode::OK
+    );
+    assert_eq!(user_flair_list(&state).await, ["auth", "helper"]);
+
+    assert_eq!(set_flairs(&state, "mod", &["auth"]).await, StatusCode::OK);
+    assign(&state, "mod", USER, "helper").await;
+    assert_eq!(remove(&state, "mod", USER).await, StatusCode::OK);
+    assert_eq!(user_flair_list(&state).await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn approvals_keep_the_flairs_mods_gave() {
+    let state = multi_flair_state().await;
+    set_settings(
+        &state,
+        "mod",
+        CommunitySettings {
+            max_flairs: 2,
+            require_approval: true,
+            ..CommunitySettings::new(COMMUNITY.to_string())
+        },
+    )
+    .await;
+    assign(&state, "mod", USER, "helper").await;
+
+    assert_eq!(
+        assign(&state, "user", USER, "auth").await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(decide(&state, "mod", true).await, StatusCode::OK);
+    assert_eq!(user_flair_list(&state).await, ["helper", "auth"]);
+
+    // v1 keeps treating flairs as a single one: the first is returned, and every flair is removed at once
+    assert_eq!(user_flair(&state, USER).await.as_deref(), Some("helper"));
+    assert_eq!(remove(&state, "mod", USER).await, StatusCode::OK);
+    assert_eq!(user_flair_list(&state).await, Vec::<String>::new());
+
+    // The removal records both flairs, without naming a single one
+    let removal = &audit_log(&state, "mod").await.unwrap()[0];
+    assert_eq!(removal.action, AuditAction::UserFlairRemoved);
+    assert_eq!(removal.flair_name, None);
+    assert_eq!(
+        removal.before.as_ref().unwrap().as_array().unwrap().len(),
+        2
+    );
+}
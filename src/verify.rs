@@ -1,26 +1,48 @@
-use reqwest::header::COOKIE;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::header::{AUTHORIZATION, COOKIE};
+use reqwest::{RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use sha2::{Digest, Sha256};
+
+use crate::config::{is_valid_domain, Config, InstancesConfig};
+use crate::error::{ApiError, ErrorCode};
 
+/// Response of `/api/v3/site`, in the shape shared by Lemmy 0.18 and 0.19
 #[derive(Serialize, Deserialize)]
 struct Site {
-    my_user: MyUser,
+    version: String,
+    /// Missing if the request isn't authenticated
+    my_user: Option<MyUser>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct MyUser {
     moderates: Vec<Moderate>,
-    local_user_view: LocalUser,
+    local_user_view: LocalUserView,
 }
 
 #[derive(Serialize, Deserialize)]
-struct LocalUser {
+struct LocalUserView {
     person: Person,
+    /// Lemmy 0.19+ only
+    local_user: Option<LocalUser>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LocalUser {
+    /// Lemmy 0.19+ moved the admin flag here
+    admin: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Person {
     actor_id: String,
+    /// Lemmy 0.18 and older only
+    admin: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,76 +55,764 @@ struct Community {
     actor_id: String,
 }
 
-///Polls the Lemmy API, verifies if a user is allowed to edit a flair
-/// - **Mods** are allowed to change everyone's flair within the community they moderate
-/// - **Users** can only change their own flair
-pub async fn verify_user(
-    lemmy_port: &u16,
-    docker: &bool,
-    jwt: &str,
-    user_actor_id: &str,
-    community_actor_id: &str,
-    local_domain: &str,
-    user_domain: &str,
-) -> Result<bool, Box<dyn Error>> {
-    let url = get_url(lemmy_port, jwt, local_domain, user_domain, docker);
-
-    let cookie = format!("jwt={}", jwt);
-
-    let client = reqwest::Client::new();
-    let res = client.get(&url).header(COOKIE, cookie).send().await?;
-
-    let json: Site = res.json().await?;
-    let moderated = &json
-        .my_user
-        .moderates
-        .into_iter()
-        .map(|el| el.community.actor_id)
-        .collect::<Vec<String>>();
-    let person_actor_id = json.my_user.local_user_view.person.actor_id;
-
-    Ok(person_actor_id == user_actor_id || moderated.contains(&community_actor_id.to_string()))
-}
-
-///Polls the Lemmy API, verifies if a user is a community moderator
-/// - **Mods** are allowed to add and delete community flairs
-/// - **Users** aren't allowed to do anything
-pub async fn verify_mod(
-    lemmy_port: &u16,
-    docker: &bool,
-    jwt: &str,
-    community_actor_id: &str,
-    local_domain: &str,
-    user_domain: &str,
-) -> Result<bool, Box<dyn Error>> {
-    let url = get_url(lemmy_port, jwt, local_domain, user_domain, docker);
-
-    let cookie = format!("jwt={}", jwt);
-
-    let client = reqwest::Client::new();
-    let res = client.get(&url).header(COOKIE, cookie).send().await?;
-
-    let json: Site = res.json().await?;
-    let moderated = &json
-        .my_user
-        .moderates
-        .into_iter()
-        .map(|el| el.community.actor_id)
-        .collect::<Vec<String>>();
-
-    Ok(moderated.contains(&community_actor_id.to_string()))
-}
-
-fn get_url(port: &u16, jwt: &str, local_domain: &str, user_domain: &str, docker: &bool) -> String {
-    let url = if local_domain.eq(user_domain) {
-        if *docker {
-            format!("http://lemmy:{}/api/v3/site?auth={}", port, jwt)
+/// Response of `/api/v3/community`
+#[derive(Serialize, Deserialize)]
+struct GetCommunity {
+    community_view: CommunityView,
+    #[serde(default)]
+    moderators: Vec<CommunityModerator>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CommunityView {
+    community: LemmyActor,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CommunityModerator {
+    moderator: Person,
+}
+
+/// Response of `/api/v3/user`
+#[derive(Serialize, Deserialize)]
+struct GetPerson {
+    person_view: PersonView,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersonView {
+    person: LemmyActor,
+}
+
+/// Response of `/api/v3/resolve_object`, only one of the fields is set
+#[derive(Serialize, Deserialize)]
+struct ResolveObject {
+    community: Option<CommunityView>,
+    person: Option<PersonView>,
+}
+
+/// Community or person, as returned by the Lemmy API
+#[derive(Serialize, Deserialize)]
+struct LemmyActor {
+    actor_id: String,
+    #[serde(default)]
+    deleted: bool,
+    /// Communities only, removed by the admins
+    #[serde(default)]
+    removed: bool,
+}
+
+/// Kind of actor a flair refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActorKind {
+    Community,
+    Person,
+}
+
+impl ActorKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ActorKind::Community => "community",
+            ActorKind::Person => "user",
+        }
+    }
+}
+
+/// How the JWT is sent to the Lemmy API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LemmyApi {
+    /// Lemmy 0.18 and older: `auth` query parameter and `jwt` cookie
+    Legacy,
+    /// Lemmy 0.19 and newer: `Authorization: Bearer` header, which keeps tokens out of access logs
+    Bearer,
+}
+
+impl LemmyApi {
+    /// Picks the authentication method from a Lemmy version (eg: `0.19.3`), `None` if it can't be parsed
+    pub fn from_version(version: &str) -> Option<Self> {
+        let mut parts = version.trim_start_matches('v').split('.');
+        let major: u32 = parts.next()?.parse().ok()?;
+        let minor: u32 = parts.next()?.parse().ok()?;
+
+        if major == 0 && minor < 19 {
+            Some(LemmyApi::Legacy)
         } else {
-            format!("http://127.0.0.1:{}/api/v3/site?auth={}", port, jwt)
+            Some(LemmyApi::Bearer)
         }
-    } else {
-        format!("https://{}/api/v3/site?auth={}", user_domain, jwt)
+    }
+}
+
+/// Which remote instances are trusted to verify the JWTs of their users.
+/// Whatever the policy, an instance can only vouch for users living on its own domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstancePolicy {
+    /// Every instance is trusted
+    All,
+    /// Only the listed instances are trusted
+    Allowlist,
+    /// Every instance but the listed ones is trusted
+    Denylist,
+    /// Only the local instance is trusted
+    LocalOnly,
+}
+
+impl FromStr for InstancePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(InstancePolicy::All),
+            "allowlist" => Ok(InstancePolicy::Allowlist),
+            "denylist" => Ok(InstancePolicy::Denylist),
+            "local_only" => Ok(InstancePolicy::LocalOnly),
+            _ => Err(format!("unknown instance policy '{s}'")),
+        }
+    }
+}
+
+/// Why a user is allowed to edit a flair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The user moderates the community, community settings don't apply to them
+    Mod,
+    /// The user is an admin of the instance hosting the community, acting as a mod
+    Admin,
+    /// The user is editing their own flair
+    Owner,
+}
+
+impl Role {
+    /// Whether the user has the powers of a community mod
+    pub fn is_mod(&self) -> bool {
+        matches!(self, Role::Mod | Role::Admin)
+    }
+}
+
+/// Verified is a user allowed to perform an operation, as told by Lemmy
+#[derive(Debug, Clone)]
+pub struct Verified {
+    pub actor_id: String,
+    pub role: Role,
+}
+
+/// LemmyUser is the Lemmy account owning a JWT
+#[derive(Debug, Clone)]
+pub struct LemmyUser {
+    pub actor_id: String,
+    /// Actor ids of the communities moderated by the user
+    pub moderates: Vec<String>,
+    /// Whether the user is an admin of the local instance, admins of other instances never count
+    pub admin: bool,
+}
+
+impl LemmyUser {
+    pub fn is_mod(&self, community_actor_id: &str) -> bool {
+        self.moderates.iter().any(|c| c == community_actor_id)
+    }
+}
+
+/// Cached users are keyed by the instance that issued the JWT and by the hash of the JWT,
+/// so that tokens are never kept in memory
+type CacheKey = (String, [u8; 32]);
+
+/// Verifier polls the Lemmy API to find out who's making a request.
+/// Users are cached for a while, so that a mod editing dozens of flairs doesn't flood Lemmy.
+pub struct Verifier {
+    client: reqwest::Client,
+    /// Address of the local Lemmy API (eg: `http://lemmy:8536`), without a trailing slash
+    internal_url: String,
+    /// Domain of the Lemmy instance running next to Flair
+    local_domain: String,
+    /// Authentication method of every instance, probed for each instance if `None`
+    api: Option<LemmyApi>,
+    /// Remote instances allowed to verify JWTs
+    instances: InstancesConfig,
+    /// Whether local admins can act as mods of the local communities
+    admin_override: bool,
+    /// Authentication methods probed so far, by instance domain
+    probed: Mutex<HashMap<String, LemmyApi>>,
+    /// How long users are cached for, no caching if zero
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<CacheKey, (Instant, LemmyUser)>>,
+    /// Whether communities and users are looked up on Lemmy before being written
+    validate_actors: bool,
+    /// How long existing actors are remembered for, unknown ones are never cached
+    actor_cache_ttl: Duration,
+    actors: Mutex<HashMap<(ActorKind, String), Instant>>,
+}
+
+impl Verifier {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            internal_url: config.lemmy.internal_url.clone(),
+            local_domain: config.lemmy.domain.clone(),
+            api: config.lemmy.api(),
+            instances: config.instances.clone(),
+            admin_override: config.lemmy.admin_override,
+            probed: Mutex::new(HashMap::new()),
+            cache_ttl: Duration::from_secs(config.lemmy.auth_cache_ttl),
+            cache: Mutex::new(HashMap::new()),
+            validate_actors: config.lemmy.validate_actors,
+            actor_cache_ttl: Duration::from_secs(config.lemmy.actor_cache_ttl),
+            actors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Verifies if a user is allowed to edit a flair
+    /// - **Mods** are allowed to change everyone's flair within the community they moderate
+    /// - **Users** can only change their own flair
+    ///
+    /// Users are fetched from the cache if possible:
+    /// - **Untrusted** instances result in an `untrusted_instance` error
+    /// - **Unreachable** instances (or instances failing to answer) result in a `lemmy_unreachable` error
+    /// - **Invalid** JWTs result in an `unauthorized` error
+    ///
+    /// Returns `None` if the user isn't allowed to edit the flair
+    pub async fn verify_user(
+        &self,
+        jwt: &str,
+        user_actor_id: &str,
+        community_actor_id: &str,
+        user_domain: &str,
+    ) -> Result<Option<Verified>, ApiError> {
+        let role = |user: &LemmyUser| {
+            self.mod_role(user, community_actor_id).or_else(|| {
+                if user.actor_id == user_actor_id {
+                    Some(Role::Owner)
+                } else {
+                    None
+                }
+            })
+        };
+
+        let (mut user, cached) = self.lookup(jwt, user_domain).await?;
+        let mut verified = role(&user);
+        if verified.is_none() && cached {
+            user = self.refresh(jwt, user_domain).await?;
+            verified = role(&user);
+        }
+
+        Ok(verified.map(|role| Verified {
+            actor_id: user.actor_id,
+            role,
+        }))
+    }
+
+    ///Verifies if a user is a community moderator
+    /// - **Mods** are allowed to add and delete community flairs
+    /// - **Users** aren't allowed to do anything
+    pub async fn verify_mod(
+        &self,
+        jwt: &str,
+        community_actor_id: &str,
+        user_domain: &str,
+    ) -> Result<Option<Verified>, ApiError> {
+        let (mut user, cached) = self.lookup(jwt, user_domain).await?;
+        let mut role = self.mod_role(&user, community_actor_id);
+        if role.is_none() && cached {
+            user = self.refresh(jwt, user_domain).await?;
+            role = self.mod_role(&user, community_actor_id);
+        }
+
+        Ok(role.map(|role| Verified {
+            actor_id: user.actor_id,
+            role,
+        }))
+    }
+
+    ///Checks whether a user has the powers of a community mod
+    /// - **Mods** of the community always do
+    /// - **Admins** of the local instance do in the communities it hosts, unless the admin override is disabled
+    ///
+    /// Admins acting as mods are logged, as they usually aren't part of the community
+    fn mod_role(&self, user: &LemmyUser, community_actor_id: &str) -> Option<Role> {
+        if user.is_mod(community_actor_id) {
+            return Some(Role::Mod);
+        }
+
+        if self.admin_override && user.admin && lives_on(community_actor_id, &self.local_domain) {
+            tracing::info!(
+                target: "flair::audit",
+                "admin {} is acting as a mod of {}",
+                user.actor_id,
+                community_actor_id
+            );
+            return Some(Role::Admin);
+        }
+
+        None
+    }
+
+    ///Makes sure a community or user exists on Lemmy before flairs are written for it, if enabled.
+    /// Actors are looked up on the local instance, which federates with the remote ones.
+    /// Actors the local instance hasn't seen yet are resolved through it, with the JWT of the user if they're local,
+    /// as Lemmy only fetches remote objects on behalf of its own users.
+    /// - **Unknown** and **deleted** actors result in an `unknown_actor` error
+    /// - **Malformed** actor ids result in a `validation_failed` error
+    pub async fn check_actor(
+        &self,
+        kind: ActorKind,
+        actor_id: &str,
+        jwt: &str,
+        user_domain: &str,
+    ) -> Result<(), ApiError> {
+        if !self.validate_actors {
+            return Ok(());
+        }
+
+        let key = (kind, actor_id.to_string());
+        let now = Instant::now();
+        if let Ok(actors) = self.actors.lock() {
+            if actors.get(&key).is_some_and(|expires_on| *expires_on > now) {
+                return Ok(());
+            }
+        }
+
+        let actor = match self.find_actor(kind, actor_id).await? {
+            Some(actor) => Some(actor),
+            None => self.resolve_actor(kind, actor_id, jwt, user_domain).await?,
+        };
+
+        match actor {
+            Some(actor) if !actor.deleted && !actor.removed => {
+                if let Ok(mut actors) = self.actors.lock() {
+                    actors.retain(|_, expires_on| *expires_on > now);
+                    actors.insert(key, now + self.actor_cache_ttl);
+                }
+                Ok(())
+            }
+            _ => Err(ApiError::new(
+                ErrorCode::UnknownActor,
+                format!("Unknown or deleted {} '{actor_id}'", kind.name()),
+            )),
+        }
+    }
+
+    /// Looks an actor up by name on the local instance, `None` if it doesn't know the actor
+    async fn find_actor(
+        &self,
+        kind: ActorKind,
+        actor_id: &str,
+    ) -> Result<Option<LemmyActor>, ApiError> {
+        // Lemmy looks actors up by name, in the `name@domain` form
+        let invalid = || ApiError::validation(format!("Invalid {} '{actor_id}'", kind.name()));
+        let url = reqwest::Url::parse(actor_id).map_err(|_| invalid())?;
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(invalid)?;
+        let domain = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(invalid()),
+        };
+        let identifier = format!("{name}@{domain}");
+
+        let request = match kind {
+            ActorKind::Community => self
+                .client
+                .get(format!("{}/api/v3/community", self.internal_url))
+                .query(&[("name", &identifier)]),
+            ActorKind::Person => self
+                .client
+                .get(format!("{}/api/v3/user", self.internal_url))
+                .query(&[("username", &identifier)]),
+        };
+        let res = request
+            .send()
+            .await
+            .map_err(|e| lemmy_unreachable(&self.local_domain, e.without_url()))?;
+
+        if res.status().is_server_error() {
+            return Err(lemmy_unreachable(&self.local_domain, res.status()));
+        }
+
+        // Lemmy answers with an error if it doesn't know the actor
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        let actor = match kind {
+            ActorKind::Community => res
+                .json::<GetCommunity>()
+                .await
+                .ok()
+                .map(|c| c.community_view.community),
+            ActorKind::Person => res
+                .json::<GetPerson>()
+                .await
+                .ok()
+                .map(|p| p.person_view.person),
+        };
+
+        Ok(actor.filter(|actor| actor.actor_id.eq_ignore_ascii_case(actor_id)))
+    }
+
+    /// Has the local instance fetch an actor from its own instance, `None` if it can't be resolved.
+    /// Remote users can't vouch for the request, Lemmy then only resolves the actors it already knows.
+    async fn resolve_actor(
+        &self,
+        kind: ActorKind,
+        actor_id: &str,
+        jwt: &str,
+        user_domain: &str,
+    ) -> Result<Option<LemmyActor>, ApiError> {
+        let mut request = self
+            .client
+            .get(format!("{}/api/v3/resolve_object", self.internal_url))
+            .query(&[("q", actor_id)]);
+        if self.is_local(user_domain) {
+            let api = self.api(&self.local_domain).await?;
+            request = authenticate(request, api, jwt);
+        }
+
+        let res = request
+            .send()
+            .await
+            .map_err(|e| lemmy_unreachable(&self.local_domain, e.without_url()))?;
+
+        if res.status().is_server_error() {
+            return Err(lemmy_unreachable(&self.local_domain, res.status()));
+        }
+
+        // Lemmy answers with an error if the object can't be fetched
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        let actor = res
+            .json::<ResolveObject>()
+            .await
+            .ok()
+            .and_then(|object| match kind {
+                ActorKind::Community => object.community.map(|c| c.community),
+                ActorKind::Person => object.person.map(|p| p.person),
+            });
+
+        Ok(actor.filter(|actor| actor.actor_id.eq_ignore_ascii_case(actor_id)))
+    }
+
+    /// Returns the user owning a JWT and whether it came from the cache
+    async fn lookup(&self, jwt: &str, user_domain: &str) -> Result<(LemmyUser, bool), ApiError> {
+        self.check_instance(user_domain)?;
+
+        let key = cache_key(jwt, user_domain);
+        if let Some(user) = self.cached(&key) {
+            return Ok((user, true));
+        }
+
+        let user = self.fetch(jwt, user_domain).await?;
+        self.store(key, user.clone());
+
+        Ok((user, false))
+    }
+
+    /// Drops the cached user and asks Lemmy again, as a denied request might be due to stale data
+    /// (eg: the user was made a mod after being cached)
+    async fn refresh(&self, jwt: &str, user_domain: &str) -> Result<LemmyUser, ApiError> {
+        self.invalidate(&cache_key(jwt, user_domain));
+
+        Ok(self.lookup(jwt, user_domain).await?.0)
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<LemmyUser> {
+        let cache = self.cache.lock().ok()?;
+
+        match cache.get(key) {
+            Some((expires_on, user)) if *expires_on > Instant::now() => Some(user.clone()),
+            _ => None,
+        }
+    }
+
+    fn store(&self, key: CacheKey, user: LemmyUser) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+
+        if let Ok(mut cache) = self.cache.lock() {
+            let now = Instant::now();
+            cache.retain(|_, (expires_on, _)| *expires_on > now);
+            cache.insert(key, (now + self.cache_ttl, user));
+        }
+    }
+
+    fn invalidate(&self, key: &CacheKey) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.remove(key);
+        }
+    }
+
+    fn is_local(&self, user_domain: &str) -> bool {
+        self.local_domain.eq_ignore_ascii_case(user_domain)
+    }
+
+    /// Makes sure an instance can be asked to verify a JWT, before any request is sent to it
+    fn check_instance(&self, user_domain: &str) -> Result<(), ApiError> {
+        if self.is_local(user_domain) {
+            return Ok(());
+        }
+        if !is_valid_domain(user_domain) {
+            return Err(ApiError::validation(format!(
+                "'{user_domain}' isn't a valid instance domain"
+            )));
+        }
+        if !self.instances.trusts(user_domain) {
+            return Err(ApiError::new(
+                ErrorCode::UntrustedInstance,
+                format!("The {user_domain} instance isn't trusted by this server"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns how an instance expects the JWT, asking for its version the first time if not configured
+    async fn api(&self, user_domain: &str) -> Result<LemmyApi, ApiError> {
+        if let Some(api) = self.api {
+            return Ok(api);
+        }
+
+        if let Some(api) = self
+            .probed
+            .lock()
+            .ok()
+            .and_then(|p| p.get(user_domain).copied())
+        {
+            return Ok(api);
+        }
+
+        // Anonymous requests get the site, and its version, without a user
+        let site: Site = self
+            .client
+            .get(format!("{}/api/v3/site", self.base_url(user_domain)))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| lemmy_unreachable(user_domain, e.without_url()))?
+            .json()
+            .await
+            .map_err(|e| lemmy_unreachable(user_domain, e))?;
+
+        let api = LemmyApi::from_version(&site.version).unwrap_or(LemmyApi::Bearer);
+        tracing::debug!(
+            "the {} Lemmy instance runs version {}, using {:?} authentication",
+            user_domain,
+            site.version,
+            api
+        );
+        if let Ok(mut probed) = self.probed.lock() {
+            probed.insert(user_domain.to_string(), api);
+        }
+
+        Ok(api)
+    }
+
+    /// Address of an instance's API: the local instance is reached through its internal url, federated ones through HTTPS
+    fn base_url(&self, user_domain: &str) -> String {
+        if self.is_local(user_domain) {
+            self.internal_url.clone()
+        } else {
+            format!("https://{}", user_domain)
+        }
+    }
+
+    ///Polls the Lemmy API, fetches the user owning a JWT
+    async fn fetch(&self, jwt: &str, user_domain: &str) -> Result<LemmyUser, ApiError> {
+        let api = self.api(user_domain).await?;
+
+        let request = self
+            .client
+            .get(format!("{}/api/v3/site", self.base_url(user_domain)));
+        let res = authenticate(request, api, jwt)
+            .send()
+            .await
+            .map_err(|e| lemmy_unreachable(user_domain, e.without_url()))?;
+
+        if res.status() == StatusCode::UNAUTHORIZED {
+            // The JWT was revoked (eg: the user logged out), forget everything we knew about it
+            self.invalidate(&cache_key(jwt, user_domain));
+            return Err(ApiError::unauthorized());
+        }
+
+        if res.status().is_server_error() {
+            return Err(lemmy_unreachable(user_domain, res.status()));
+        }
+
+        // Lemmy omits the user (or answers with an error) if the JWT isn't valid
+        let my_user = res
+            .json::<Site>()
+            .await
+            .ok()
+            .and_then(|site| site.my_user)
+            .ok_or_else(ApiError::unauthorized)?;
+
+        // A remote instance could otherwise claim to be any user, including the mods of local communities
+        let actor_id = my_user.local_user_view.person.actor_id;
+        if !self.is_local(user_domain) && !lives_on(&actor_id, user_domain) {
+            tracing::warn!(
+                "the {} Lemmy instance vouched for {}, who doesn't live on it",
+                user_domain,
+                actor_id
+            );
+            return Err(ApiError::unauthorized());
+        }
+
+        // Lemmy 0.19 moved the admin flag from the person to the local user
+        let admin = my_user
+            .local_user_view
+            .local_user
+            .and_then(|local_user| local_user.admin)
+            .or(my_user.local_user_view.person.admin)
+            .unwrap_or(false);
+
+        let mut moderates = my_user
+            .moderates
+            .into_iter()
+            .map(|el| el.community.actor_id)
+            .collect();
+        if !self.is_local(user_domain) {
+            moderates = self
+                .confirm_moderates(&actor_id, moderates, user_domain)
+                .await?;
+        }
+
+        Ok(LemmyUser {
+            actor_id,
+            moderates,
+            admin: admin && self.is_local(user_domain),
+        })
+    }
+
+    ///Checks the communities a remote instance claims its user moderates.
+    /// The instance is only trusted for the communities it hosts, any other claim is dropped
+    /// unless the local instance lists the user among the community mods.
+    async fn confirm_moderates(
+        &self,
+        actor_id: &str,
+        moderates: Vec<String>,
+        user_domain: &str,
+    ) -> Result<Vec<String>, ApiError> {
+        let mut confirmed = vec![];
+        for community_actor_id in moderates {
+            if !lives_on(&community_actor_id, user_domain)
+                && !self
+                    .local_moderators(&community_actor_id)
+                    .await?
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(actor_id))
+            {
+                tracing::warn!(
+                    "the {} Lemmy instance claimed {} moderates {}, which the local instance doesn't confirm",
+                    user_domain,
+                    actor_id,
+                    community_actor_id
+                );
+                continue;
+            }
+
+            confirmed.push(community_actor_id);
+        }
+
+        Ok(confirmed)
+    }
+
+    /// Actor ids of the mods of a community, as known by the local instance. Empty if it doesn't know the community
+    async fn local_moderators(&self, community_actor_id: &str) -> Result<Vec<String>, ApiError> {
+        let Some(identifier) = identifier(community_actor_id) else {
+            return Ok(vec![]);
+        };
+
+        let res = self
+            .client
+            .get(format!("{}/api/v3/community", self.internal_url))
+            .query(&[("name", &identifier)])
+            .send()
+            .await
+            .map_err(|e| lemmy_unreachable(&self.local_domain, e.without_url()))?;
+
+        if res.status().is_server_error() {
+            return Err(lemmy_unreachable(&self.local_domain, res.status()));
+        }
+
+        // Lemmy answers with an error if it doesn't know the community
+        if !res.status().is_success() {
+            return Ok(vec![]);
+        }
+        let moderators = res
+            .json::<GetCommunity>()
+            .await
+            .ok()
+            .filter(|c| {
+                c.community_view
+                    .community
+                    .actor_id
+                    .eq_ignore_ascii_case(community_actor_id)
+            })
+            .map(|c| {
+                c.moderators
+                    .into_iter()
+                    .map(|m| m.moderator.actor_id)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(moderators)
+    }
+}
+
+/// Name Lemmy looks an actor up by, in the `name@domain` form (eg: `https://lemmy.ml/u/nutomic` is `nutomic@lemmy.ml`)
+fn identifier(actor_id: &str) -> Option<String> {
+    let url = reqwest::Url::parse(actor_id).ok()?;
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())?;
+    let domain = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return None,
+    };
+
+    Some(format!("{name}@{domain}"))
+}
+
+/// Whether an actor id belongs to an instance (eg: `https://lemmy.ml/u/nutomic` lives on `lemmy.ml`)
+fn lives_on(actor_id: &str, domain: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(actor_id) else {
+        return false;
+    };
+
+    let actor_domain = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return false,
     };
 
-    url
+    actor_domain.eq_ignore_ascii_case(domain)
+}
+
+/// Sends a JWT the way an instance expects it
+fn authenticate(request: RequestBuilder, api: LemmyApi, jwt: &str) -> RequestBuilder {
+    match api {
+        LemmyApi::Legacy => request
+            .query(&[("auth", jwt)])
+            .header(COOKIE, format!("jwt={}", jwt)),
+        LemmyApi::Bearer => request.header(AUTHORIZATION, format!("Bearer {}", jwt)),
+    }
 }
+
+fn cache_key(jwt: &str, user_domain: &str) -> CacheKey {
+    (user_domain.to_string(), Sha256::digest(jwt).into())
+}
+
+fn lemmy_unreachable(domain: &str, err: impl std::fmt::Display) -> ApiError {
+    tracing::warn!("unable to reach the {} Lemmy instance: {}", domain, err);
+    ApiError::new(
+        ErrorCode::LemmyUnreachable,
+        format!("Unable to reach the {domain} Lemmy instance"),
+    )
+}
+
+#[cfg(test)]
+mod tests;
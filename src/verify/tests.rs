@@ -0,0 +1,269 @@
+//! Verifier tests, backed by fake local Lemmy instances emulating the 0.18 and 0.19 authentication.
+//! Remote instances can't be faked, their claims are checked directly.
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap};
+use axum::{routing, Json, Router};
+use serde_json::{json, Value};
+
+use super::*;
+use crate::config::Config;
+
+const DOMAIN: &str = "lemmy.test";
+const USER: &str = "https://lemmy.test/u/user";
+const COMMUNITY: &str = "https://lemmy.test/c/flairs";
+const LOCAL_MOD: &str = "https://lemmy.test/u/mod";
+/// Mod of the local community living on another instance
+const REMOTE_MOD: &str = "https://remote.test/u/mod";
+/// Community of a third instance, known by the local one
+const FEDERATED_COMMUNITY: &str = "https://third.test/c/federated";
+
+/// Fake Lemmy instance, the only valid JWT being `user`
+struct FakeLemmy {
+    version: &'static str,
+    /// Number of anonymous requests, made to find out the version
+    probes: AtomicUsize,
+}
+
+impl FakeLemmy {
+    fn legacy(&self) -> bool {
+        self.version.starts_with("0.18")
+    }
+}
+
+/// Reads the JWT where the emulated Lemmy version expects it: the `auth` query parameter
+/// up to 0.18, the `Authorization` header since 0.19
+async fn site(
+    State(lemmy): State<Arc<FakeLemmy>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let jwt = if lemmy.legacy() {
+        params.get("auth").cloned()
+    } else {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::to_string)
+    };
+
+    match jwt.as_deref() {
+        None => {
+            lemmy.probes.fetch_add(1, Ordering::SeqCst);
+            Ok(Json(json!({ "version": lemmy.version })))
+        }
+        Some("user") => Ok(Json(json!({
+            "version": lemmy.version,
+            "my_user": {
+                "moderates": [],
+                "local_user_view": { "person": { "actor_id": USER } },
+            },
+        }))),
+        // 0.18 rejects bad tokens with a 400, 0.19 with a 401
+        Some(_) if lemmy.legacy() => Err(StatusCode::BAD_REQUEST),
+        Some(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Answers like Lemmy does for the communities it knows: the local one, moderated by a local and a remote user,
+/// and one of a third instance moderated by the remote user
+async fn community(
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let (actor_id, moderators) = match params.get("name").map(String::as_str) {
+        Some("flairs@lemmy.test") => (COMMUNITY, vec![LOCAL_MOD, REMOTE_MOD]),
+        Some("federated@third.test") => (FEDERATED_COMMUNITY, vec![REMOTE_MOD]),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    Ok(Json(json!({
+        "community_view": { "community": { "actor_id": actor_id } },
+        "moderators": moderators
+            .iter()
+            .map(|actor_id| json!({ "moderator": { "actor_id": actor_id } }))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+/// Starts a fake local Lemmy instance running a version, returns its port and state
+fn start_lemmy(version: &'static str) -> (u16, Arc<FakeLemmy>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let lemmy = Arc::new(FakeLemmy {
+        version,
+        probes: AtomicUsize::new(0),
+    });
+    let app = Router::new()
+        .route("/api/v3/site", routing::get(site))
+        .route("/api/v3/community", routing::get(community))
+        .with_state(lemmy.clone());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+
+    (port, lemmy)
+}
+
+/// Verifier using the fake Lemmy instance, `api_version` being `auto` or a Lemmy version
+fn verifier(port: u16, api_version: &str) -> Verifier {
+    let internal_url = format!("http://127.0.0.1:{port}");
+
+    Verifier::new(
+        &Config::load_with_env(None, &|var| match var {
+            "LEMMY_DOMAIN" => Some(DOMAIN.to_string()),
+            "LEMMY_INTERNAL_URL" => Some(internal_url.clone()),
+            "LEMMY_API_VERSION" => Some(api_version.to_string()),
+            "AUTH_CACHE_TTL" => Some("0".to_string()),
+            _ => None,
+        })
+        .unwrap(),
+    )
+}
+
+#[test]
+fn api_is_picked_from_version() {
+    assert_eq!(LemmyApi::from_version("0.18.5"), Some(LemmyApi::Legacy));
+    assert_eq!(LemmyApi::from_version("0.17"), Some(LemmyApi::Legacy));
+    assert_eq!(LemmyApi::from_version("0.19.0"), Some(LemmyApi::Bearer));
+    assert_eq!(LemmyApi::from_version("v0.19.3"), Some(LemmyApi::Bearer));
+    assert_eq!(LemmyApi::from_version("1.0.0-beta"), Some(LemmyApi::Bearer));
+    assert_eq!(LemmyApi::from_version("0"), None);
+    assert_eq!(LemmyApi::from_version("auto"), None);
+}
+
+#[tokio::test]
+async fn version_is_probed_once_per_instance() {
+    for version in ["0.18.5", "0.19.3"] {
+        let (port, lemmy) = start_lemmy(version);
+        let verifier = verifier(port, "auto");
+
+        for _ in 0..2 {
+            let (user, _) = verifier.lookup("user", DOMAIN).await.unwrap();
+            assert_eq!(user.actor_id, USER);
+        }
+        assert_eq!(lemmy.probes.load(Ordering::SeqCst), 1, "{version}");
+    }
+}
+
+#[tokio::test]
+async fn configured_api_skips_probing() {
+    let (port, lemmy) = start_lemmy("0.19.3");
+
+    let (user, _) = verifier(port, "0.19").lookup("user", DOMAIN).await.unwrap();
+    assert_eq!(user.actor_id, USER);
+    assert_eq!(lemmy.probes.load(Ordering::SeqCst), 0);
+
+    // 0.19 doesn't read the `auth` query parameter anymore, the JWT is ignored
+    let err = verifier(port, "0.18")
+        .lookup("user", DOMAIN)
+        .await
+        .unwrap_err();
+    assert_eq!(err.error, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn invalid_jwts_are_unauthorized() {
+    for version in ["0.18.5", "0.19.3"] {
+        let (port, _) = start_lemmy(version);
+
+        let err = verifier(port, "auto")
+            .lookup("stranger", DOMAIN)
+            .await
+            .unwrap_err();
+        assert_eq!(err.error, ErrorCode::Unauthorized, "{version}");
+    }
+}
+
+#[test]
+fn jwts_are_sent_the_way_instances_expect() {
+    let client = reqwest::Client::new();
+    let request = |api| {
+        authenticate(client.get("http://lemmy.test/api/v3/site"), api, "token")
+            .build()
+            .unwrap()
+    };
+
+    let legacy = request(LemmyApi::Legacy);
+    assert_eq!(legacy.url().query(), Some("auth=token"));
+    assert_eq!(legacy.headers()[COOKIE], "jwt=token");
+    assert!(!legacy.headers().contains_key(AUTHORIZATION));
+
+    let bearer = request(LemmyApi::Bearer);
+    assert_eq!(bearer.url().query(), None);
+    assert_eq!(bearer.headers()[AUTHORIZATION], "Bearer token");
+    assert!(!bearer.headers().contains_key(COOKIE));
+}
+
+#[tokio::test]
+async fn remote_mods_are_confirmed_by_the_local_instance() {
+    let (port, _) = start_lemmy("0.19.3");
+
+    let moderates = verifier(port, "auto")
+        .confirm_moderates(
+            REMOTE_MOD,
+            vec![
+                COMMUNITY.to_string(),
+                FEDERATED_COMMUNITY.to_string(),
+                "https://remote.test/c/own".to_string(),
+            ],
+            "remote.test",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        moderates,
+        [COMMUNITY, FEDERATED_COMMUNITY, "https://remote.test/c/own"]
+    );
+}
+
+#[tokio::test]
+async fn malicious_instances_cant_claim_other_communities() {
+    let (port, _) = start_lemmy("0.19.3");
+
+    // The evil.test instance claims its user moderates communities it doesn't host, including unknown ones
+    let moderates = verifier(port, "auto")
+        .confirm_moderates(
+            "https://evil.test/u/mallory",
+            vec![
+                COMMUNITY.to_string(),
+                "https://lemmy.test/c/missing".to_string(),
+                FEDERATED_COMMUNITY.to_string(),
+                "https://third.test/c/unknown".to_string(),
+                "https://evil.test/c/own".to_string(),
+            ],
+            "evil.test",
+        )
+        .await
+        .unwrap();
+
+    // Only the communities it hosts are taken at face value
+    assert_eq!(moderates, ["https://evil.test/c/own"]);
+}
+
+#[test]
+fn actors_are_looked_up_by_name_and_domain() {
+    assert_eq!(
+        identifier("https://lemmy.ml/u/nutomic").as_deref(),
+        Some("nutomic@lemmy.ml")
+    );
+    assert_eq!(
+        identifier("http://127.0.0.1:8536/c/flairs").as_deref(),
+        Some("flairs@127.0.0.1:8536")
+    );
+    assert_eq!(identifier("https://lemmy.ml/"), None);
+    assert_eq!(identifier("lemmy.ml/u/nutomic"), None);
+
+    assert!(lives_on("https://Lemmy.ML/u/nutomic", "lemmy.ml"));
+    assert!(!lives_on(
+        "https://lemmy.ml.evil.test/u/nutomic",
+        "lemmy.ml"
+    ));
+}